@@ -0,0 +1,609 @@
+use std::collections::{HashMap, HashSet};
+use std::panic::catch_unwind;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use pgrx::bgworkers::BackgroundWorker;
+use pgrx::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::collector::Report;
+use crate::crate_info::CRATE;
+use crate::notify;
+use crate::privileges;
+
+const ONE_KB: usize = 1024;
+const BOARD_SIZE: usize = 64 * ONE_KB;
+
+/**
+ Alerts are announced on this channel as they fire and resolve, independent
+ of `pg_stat_sysinfo.notify_channel`, so that listeners need not tell
+ alerts and reports apart.
+*/
+pub const CHANNEL: &str = "pg_stat_sysinfo_alerts";
+
+static BOARD: PgLwLock<AlertBoard> = PgLwLock::new();
+
+unsafe impl PGRXSharedMemory for AlertBoard {}
+
+pub fn init() {
+    pg_shmem_init!(BOARD);
+}
+
+/**
+ Alerts currently pending or firing, as last published by the cache worker.
+*/
+pub fn active() -> Vec<Alert> {
+    // See `cache_worker::reports()` for why we catch the panic.
+    catch_unwind(|| BOARD.share().read()).unwrap_or_default()
+}
+
+extension_sql!(
+    r#"
+    CREATE TABLE pg_stat_sysinfo_alert_rules (
+        name            text PRIMARY KEY,
+        metric          text NOT NULL,
+        dimensions      jsonb NOT NULL DEFAULT '{}',
+        comparison      text NOT NULL DEFAULT '>'
+                        CHECK (comparison IN ('>', '>=', '<', '<=', '=', '<>')),
+        threshold       float8 NOT NULL,
+        clear_threshold float8,
+        for_duration    interval NOT NULL DEFAULT '0s',
+        severity        text NOT NULL DEFAULT 'warning'
+                        CHECK (severity IN
+                               ('debug', 'log', 'info', 'notice', 'warning')),
+        enabled         boolean NOT NULL DEFAULT true
+    );
+
+    SELECT pg_catalog.pg_extension_config_dump('pg_stat_sysinfo_alert_rules', '');
+    "#,
+    name = "create_alert_rules",
+);
+
+/**
+ A rule from `pg_stat_sysinfo_alert_rules`. It applies to every row of a
+ report with a matching metric whose dimensions contain the rule's
+ dimensions.
+
+ To keep alerts from flapping, a rule fires only once its condition has held
+ for `for_duration` and, once firing, resolves only when the value no longer
+ satisfies the condition against `clear_threshold`.
+*/
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub name: String,
+    pub metric: String,
+    pub dimensions: Map<String, Value>,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    pub clear_threshold: f64,
+    pub for_duration: Duration,
+    pub severity: Severity,
+}
+
+impl Rule {
+    fn matches(&self, metric: &str, dimensions: &Value) -> bool {
+        metric == self.metric
+            && self
+                .dimensions
+                .iter()
+                .all(|(k, v)| dimensions.get(k) == Some(v))
+    }
+}
+
+const FIND_RULES_TABLE: &str = "
+    SELECT format('%I.pg_stat_sysinfo_alert_rules', n.nspname)
+      FROM pg_catalog.pg_extension e
+      JOIN pg_catalog.pg_namespace n ON n.oid = e.extnamespace
+     WHERE e.extname = 'pg_stat_sysinfo'";
+
+/**
+ Loads enabled rules from the database the worker is connected to. When the
+ extension has not been created there, there are no rules.
+*/
+pub fn load_rules() -> anyhow::Result<Vec<Rule>> {
+    BackgroundWorker::transaction(|| {
+        let table = match Spi::get_one::<String>(FIND_RULES_TABLE)? {
+            Some(table) => table,
+            None => return Ok(vec![]),
+        };
+        let query = format!(
+            "SELECT name, metric, dimensions::text AS dimensions,
+                    comparison, threshold,
+                    coalesce(clear_threshold, threshold) AS clear_threshold,
+                    extract(epoch FROM for_duration)::float8 AS for_seconds,
+                    severity
+               FROM {table}
+              WHERE enabled"
+        );
+
+        Spi::connect(|client| {
+            let mut rules = vec![];
+            for row in client.select(&query, None, None)? {
+                let text = |col: &str| -> anyhow::Result<String> {
+                    row.get_by_name::<String, _>(col)?
+                        .ok_or_else(|| anyhow!("Rule has no {}", col))
+                };
+                let float = |col: &str| -> anyhow::Result<f64> {
+                    row.get_by_name::<f64, _>(col)?
+                        .ok_or_else(|| anyhow!("Rule has no {}", col))
+                };
+                let dimensions = match serde_json::from_str(&text("dimensions")?)? {
+                    Value::Object(map) => map,
+                    _ => Map::new(),
+                };
+                let for_seconds = float("for_seconds")?.max(0.0);
+
+                rules.push(Rule {
+                    name: text("name")?,
+                    metric: text("metric")?,
+                    dimensions,
+                    comparison: text("comparison")?.parse()?,
+                    threshold: float("threshold")?,
+                    clear_threshold: float("clear_threshold")?,
+                    for_duration: Duration::from_secs_f64(for_seconds),
+                    severity: text("severity")?.parse()?,
+                });
+            }
+            Ok(rules)
+        })
+    })
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Comparison {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+}
+
+impl Comparison {
+    fn holds(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Gt => value > threshold,
+            Comparison::Ge => value >= threshold,
+            Comparison::Lt => value < threshold,
+            Comparison::Le => value <= threshold,
+            Comparison::Eq => value == threshold,
+            Comparison::Ne => value != threshold,
+        }
+    }
+}
+
+impl std::str::FromStr for Comparison {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            ">" => Ok(Comparison::Gt),
+            ">=" => Ok(Comparison::Ge),
+            "<" => Ok(Comparison::Lt),
+            "<=" => Ok(Comparison::Le),
+            "=" => Ok(Comparison::Eq),
+            "<>" => Ok(Comparison::Ne),
+            _ => Err(anyhow!("Unknown comparison: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Severity {
+    Debug,
+    Log,
+    Info,
+    Notice,
+    Warning,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Debug => "debug",
+            Severity::Log => "log",
+            Severity::Info => "info",
+            Severity::Notice => "notice",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+impl std::str::FromStr for Severity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(Severity::Debug),
+            "log" => Ok(Severity::Log),
+            "info" => Ok(Severity::Info),
+            "notice" => Ok(Severity::Notice),
+            "warning" => Ok(Severity::Warning),
+            _ => Err(anyhow!("Unknown severity: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum State {
+    Pending,
+    Firing,
+    Resolved,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Pending => "pending",
+            State::Firing => "firing",
+            State::Resolved => "resolved",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Alert {
+    pub rule: String,
+    pub metric: String,
+    pub dimensions: String,
+    pub severity: Severity,
+    pub state: State,
+    pub value: f64,
+    pub threshold: f64,
+    pub since: OffsetDateTime,
+    pub at: OffsetDateTime,
+}
+
+/**
+ Tracks alert state across reports. Lives in the cache worker; the alerts
+ that are pending or firing are published to shared memory after each
+ evaluation.
+*/
+#[derive(Debug, Default)]
+pub struct Evaluator {
+    active: HashMap<(String, String), Alert>,
+}
+
+impl Evaluator {
+    /**
+     Evaluates rules against a report, returning the alerts that fired or
+     resolved.
+    */
+    pub fn evaluate(&mut self, rules: &[Rule], report: &Report) -> Vec<Alert> {
+        let mut transitions = vec![];
+        let mut seen = HashMap::new();
+
+        for (metric, dimensions, at, value) in report.rows() {
+            for rule in rules.iter().filter(|r| r.matches(&metric, &dimensions)) {
+                let key = (rule.name.clone(), dimensions.to_string());
+                seen.insert(key.clone(), ());
+
+                let alert = match self.active.remove(&key) {
+                    Some(mut alert) => {
+                        alert.value = value;
+                        alert.at = at;
+                        alert
+                    }
+                    None if rule.comparison.holds(value, rule.threshold) => Alert {
+                        rule: rule.name.clone(),
+                        metric: metric.clone(),
+                        dimensions: dimensions.to_string(),
+                        severity: rule.severity,
+                        state: State::Pending,
+                        value,
+                        threshold: rule.threshold,
+                        since: at,
+                        at,
+                    },
+                    None => continue,
+                };
+
+                match alert.state {
+                    State::Pending => {
+                        if !rule.comparison.holds(value, rule.threshold) {
+                            continue;
+                        }
+                        let held = at - alert.since;
+                        if held >= rule.for_duration {
+                            let alert = Alert {
+                                state: State::Firing,
+                                since: at,
+                                ..alert
+                            };
+                            transitions.push(alert.clone());
+                            self.active.insert(key, alert);
+                        } else {
+                            self.active.insert(key, alert);
+                        }
+                    }
+                    State::Firing => {
+                        if rule.comparison.holds(value, rule.clear_threshold) {
+                            self.active.insert(key, alert);
+                        } else {
+                            transitions.push(Alert {
+                                state: State::Resolved,
+                                since: at,
+                                ..alert
+                            });
+                        }
+                    }
+                    State::Resolved => {}
+                }
+            }
+        }
+
+        // Rules that were removed or disabled, and dimensions that have
+        // disappeared (such as an unmounted volume), no longer apply. Reports
+        // may cover only some sources, so only metrics that were reported on
+        // are considered.
+        let reported: HashSet<_> = report.samples.iter().map(|s| s.metric.as_str()).collect();
+        let gone: Vec<_> = self
+            .active
+            .iter()
+            .filter(|(key, alert)| {
+                !seen.contains_key(*key)
+                    && (!rules.iter().any(|r| r.name == alert.rule)
+                        || reported.contains(alert.metric.as_str()))
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in gone {
+            if let Some(alert) = self.active.remove(&key) {
+                if alert.state == State::Firing {
+                    transitions.push(Alert {
+                        state: State::Resolved,
+                        since: report.at,
+                        at: report.at,
+                        ..alert
+                    });
+                }
+            }
+        }
+
+        transitions
+    }
+
+    pub fn active(&self) -> Vec<Alert> {
+        let mut alerts: Vec<_> = self.active.values().cloned().collect();
+        alerts.sort_by(|a, b| (&a.rule, &a.dimensions).cmp(&(&b.rule, &b.dimensions)));
+        alerts
+    }
+
+    /**
+     Logs and announces alerts that fired or resolved, and publishes the
+     alerts that remain active.
+    */
+    pub fn publish(&self, transitions: &[Alert]) {
+        for alert in transitions {
+            announce(alert);
+        }
+
+        if let Err(e) = BOARD.exclusive().write(&self.active()) {
+            warning!("{}: Failed to publish alerts: {:?}", CRATE, e);
+        }
+    }
+}
+
+fn announce(alert: &Alert) {
+    let what = match alert.state {
+        State::Firing => "Alert firing",
+        _ => "Alert resolved",
+    };
+    let msg = format!(
+        "{}: {}: {} ({} {} = {}, threshold {})",
+        CRATE, what, alert.rule, alert.metric, alert.dimensions, alert.value, alert.threshold
+    );
+    match alert.severity {
+        Severity::Debug => debug1!("{}", msg),
+        Severity::Log => log!("{}", msg),
+        Severity::Info => info!("{}", msg),
+        Severity::Notice => notice!("{}", msg),
+        Severity::Warning => warning!("{}", msg),
+    }
+
+    // The log is for administrators, but any user may listen.
+    let dimensions: Value = serde_json::from_str(&alert.dimensions).unwrap_or(Value::Null);
+    let dimensions = privileges::public().dimensions(dimensions);
+    let payload = json!({
+        "rule": alert.rule,
+        "state": alert.state.as_str(),
+        "severity": alert.severity.as_str(),
+        "metric": alert.metric,
+        "dimensions": dimensions,
+        "value": alert.value,
+        "threshold": alert.threshold,
+        "at": alert.at.format(&Rfc3339).unwrap_or_default(),
+    });
+    notify::send(CHANNEL, &payload.to_string());
+}
+
+/**
+ Active alerts, serialized into shared memory. When there are too many to
+ fit, pending alerts are dropped first.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct AlertBoard {
+    data: heapless::Vec<u8, BOARD_SIZE>,
+}
+
+impl AlertBoard {
+    fn write(&mut self, alerts: &[Alert]) -> anyhow::Result<()> {
+        let mut alerts = alerts.to_vec();
+        alerts.sort_by_key(|a| a.state != State::Firing);
+
+        loop {
+            let encoded = serde_bare::to_vec(&alerts)?;
+            if encoded.len() <= self.data.capacity() {
+                self.data.clear();
+                let err = |_| anyhow!("Failure to extend: heapless::Vec");
+                return self.data.extend_from_slice(&encoded).map_err(err);
+            }
+            if alerts.pop().is_none() {
+                return Err(anyhow!("Alert board is too small."));
+            }
+        }
+    }
+
+    fn read(&self) -> Vec<Alert> {
+        if self.data.is_empty() {
+            return vec![];
+        }
+        serde_bare::from_slice(&self.data).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::Map;
+    use time::OffsetDateTime;
+
+    use super::{Comparison, Evaluator, Rule, Severity, State};
+    use crate::collector::{Report, Sample};
+
+    fn rule(name: &str, for_seconds: u64) -> Rule {
+        Rule {
+            name: name.into(),
+            metric: "disk_usage".into(),
+            dimensions: Map::new(),
+            comparison: Comparison::Gt,
+            threshold: 90.0,
+            clear_threshold: 80.0,
+            for_duration: Duration::from_secs(for_seconds),
+            severity: Severity::Warning,
+        }
+    }
+
+    fn report(seconds: i64, usage: &[(&str, f64)]) -> Report {
+        let at = OffsetDateTime::from_unix_timestamp(1_674_000_000 + seconds).unwrap();
+        let samples = usage
+            .iter()
+            .map(|(fs, value)| Sample::new("disk_usage", &[("fs", fs)], *value))
+            .collect();
+        Report { at, samples }
+    }
+
+    fn root(seconds: i64, usage: f64) -> Report {
+        report(seconds, &[("/", usage)])
+    }
+
+    fn states(evaluator: &Evaluator) -> Vec<State> {
+        evaluator.active().iter().map(|a| a.state).collect()
+    }
+
+    #[test]
+    fn fires_once_condition_has_held_for_duration() {
+        let rules = [rule("full", 60)];
+        let mut evaluator = Evaluator::default();
+
+        assert!(evaluator.evaluate(&rules, &root(0, 95.0)).is_empty());
+        assert_eq!(states(&evaluator), [State::Pending]);
+        assert!(evaluator.evaluate(&rules, &root(30, 95.0)).is_empty());
+        assert_eq!(states(&evaluator), [State::Pending]);
+
+        let transitions = evaluator.evaluate(&rules, &root(60, 95.0));
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].state, State::Firing);
+        assert_eq!(transitions[0].value, 95.0);
+        assert_eq!(states(&evaluator), [State::Firing]);
+    }
+
+    #[test]
+    fn resolves_only_past_clear_threshold() {
+        let rules = [rule("full", 0)];
+        let mut evaluator = Evaluator::default();
+
+        let transitions = evaluator.evaluate(&rules, &root(0, 95.0));
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].state, State::Firing);
+
+        // Below the threshold, but not below the clear threshold.
+        assert!(evaluator.evaluate(&rules, &root(10, 85.0)).is_empty());
+        assert_eq!(states(&evaluator), [State::Firing]);
+
+        let transitions = evaluator.evaluate(&rules, &root(20, 75.0));
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].state, State::Resolved);
+        assert_eq!(transitions[0].value, 75.0);
+        assert!(evaluator.active().is_empty());
+    }
+
+    #[test]
+    fn drops_pending_alert_when_condition_stops_holding() {
+        let rules = [rule("full", 60)];
+        let mut evaluator = Evaluator::default();
+
+        assert!(evaluator.evaluate(&rules, &root(0, 95.0)).is_empty());
+        assert_eq!(states(&evaluator), [State::Pending]);
+
+        // Dropped without a transition, since it never fired.
+        assert!(evaluator.evaluate(&rules, &root(30, 85.0)).is_empty());
+        assert!(evaluator.active().is_empty());
+
+        // The condition must hold for the full duration again.
+        assert!(evaluator.evaluate(&rules, &root(40, 95.0)).is_empty());
+        assert!(evaluator.evaluate(&rules, &root(90, 95.0)).is_empty());
+        assert_eq!(states(&evaluator), [State::Pending]);
+    }
+
+    #[test]
+    fn resolves_when_rule_disappears() {
+        let rules = [rule("full", 0)];
+        let mut evaluator = Evaluator::default();
+        evaluator.evaluate(&rules, &root(0, 95.0));
+        assert_eq!(states(&evaluator), [State::Firing]);
+
+        let transitions = evaluator.evaluate(&[], &root(10, 95.0));
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].rule, "full");
+        assert_eq!(transitions[0].state, State::Resolved);
+        assert!(evaluator.active().is_empty());
+    }
+
+    #[test]
+    fn resolves_when_dimensions_disappear() {
+        let rules = [rule("full", 0), rule("filling", 60)];
+        let mut evaluator = Evaluator::default();
+        let both = [("/", 95.0), ("/data", 95.0)];
+        evaluator.evaluate(&rules, &report(0, &both));
+        assert_eq!(
+            states(&evaluator),
+            [State::Pending, State::Pending, State::Firing, State::Firing]
+        );
+
+        // A report that does not cover disks leaves the alerts alone.
+        let empty = Report {
+            samples: vec![Sample::new("cpu_usage", &[], 10.0)],
+            ..report(10, &[])
+        };
+        assert!(evaluator.evaluate(&rules, &empty).is_empty());
+        assert_eq!(evaluator.active().len(), 4);
+
+        // Only the firing alert on the unmounted volume resolves; the
+        // pending one is dropped.
+        let transitions = evaluator.evaluate(&rules, &root(20, 95.0));
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].rule, "full");
+        assert_eq!(transitions[0].dimensions, r#"{"fs":"/data"}"#);
+        assert_eq!(transitions[0].state, State::Resolved);
+        assert_eq!(states(&evaluator), [State::Pending, State::Firing]);
+    }
+
+    #[test]
+    fn applies_rules_only_to_matching_dimensions() {
+        let mut data = rule("data_full", 0);
+        data.dimensions.insert("fs".into(), "/data".into());
+        let rules = [data];
+        let mut evaluator = Evaluator::default();
+
+        let both = [("/", 95.0), ("/data", 95.0)];
+        let transitions = evaluator.evaluate(&rules, &report(0, &both));
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].dimensions, r#"{"fs":"/data"}"#);
+    }
+}
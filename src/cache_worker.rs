@@ -1,32 +1,108 @@
 use std::panic::catch_unwind;
 use std::time::{Duration, Instant};
 
+use anyhow::anyhow;
+
 use pgrx::bgworkers::*;
 use pgrx::*;
+use time::OffsetDateTime;
 
+use crate::alerts;
 use crate::collector::*;
+use crate::cpu_baseline;
 use crate::crate_info::*;
+use crate::mounts::MountWatcher;
+use crate::notify;
 use crate::settings;
 use crate::shmem_ring_buffer::*;
+use crate::sources;
+use crate::worker_status;
 
 static CACHE: PgLwLock<ShmemRingBuffer<Report>> = PgLwLock::new();
 
 unsafe impl PGRXSharedMemory for ShmemRingBuffer<Report> {}
 
+/**
+ When loaded with `shared_preload_libraries`, shared memory is reserved and
+ the cache worker is started whether or not `pg_stat_sysinfo.interval` is
+ set. The worker waits for an interval to be configured, so caching can be
+ turned on with a `SIGHUP`.
+*/
 pub fn start() {
     pg_shmem_init!(CACHE);
+    worker_status::init();
+    alerts::init();
+    cpu_baseline::init();
 
-    if settings::read_or_default().interval.is_some() {
-        BackgroundWorkerBuilder::new("Cache Worker")
-            .set_function("cache_worker")
-            .set_library("pg_stat_sysinfo")
-            // We don't run any queries but, without SPI, the worker segfaults
-            // on startup.
-            .enable_spi_access()
-            .load();
+    if unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+        builder().load();
     }
 }
 
+// After an unexpected exit, the worker is started again after this long. A
+// worker that is stopped with `SIGTERM` exits cleanly and is not restarted.
+const RESTART_INTERVAL: Duration = Duration::from_secs(10);
+
+// `WaitLatch` takes its timeout as an `int` of milliseconds, so longer waits
+// are cut short; waking up early does no harm, since nothing will be due.
+const LONGEST_WAIT: Duration = Duration::from_secs(3600);
+
+fn builder() -> BackgroundWorkerBuilder {
+    BackgroundWorkerBuilder::new("Cache Worker")
+        .set_function("cache_worker")
+        .set_library("pg_stat_sysinfo")
+        // Alert rules are read, and notifications sent, with SPI.
+        .enable_spi_access()
+        .set_restart_time(Some(RESTART_INTERVAL))
+}
+
+/**
+ The process ID of the cache worker, if it is running.
+*/
+pub fn worker_pid() -> Option<i32> {
+    let pid = worker_status::read()?.pid;
+    let alive = pid != 0 && unsafe { !pg_sys::BackendPidGetProc(pid).is_null() };
+    alive.then_some(pid)
+}
+
+/**
+ Starts the cache worker, if it is not already running. This is only
+ possible when the library was loaded with `shared_preload_libraries`, since
+ otherwise there is no shared memory for the cache.
+*/
+pub fn start_dynamic() -> anyhow::Result<bool> {
+    if worker_status::read().is_none() {
+        return Err(anyhow!(
+            "{} must be loaded with shared_preload_libraries",
+            CRATE
+        ));
+    }
+    if worker_pid().is_some() {
+        return Ok(false);
+    }
+
+    builder().load_dynamic();
+    Ok(true)
+}
+
+/**
+ Stops the cache worker, if it is running. Only superusers may do this,
+ since the worker runs as the bootstrap superuser.
+*/
+pub fn stop() -> anyhow::Result<bool> {
+    let pid = match worker_pid() {
+        Some(pid) => pid,
+        None => return Ok(false),
+    };
+
+    let terminated = Spi::get_one_with_args::<bool>(
+        "SELECT pg_catalog.pg_terminate_backend($1)",
+        vec![(PgBuiltInOids::INT4OID.oid(), pid.into_datum())],
+    )?;
+
+    Ok(terminated.unwrap_or(false))
+}
+
 pub fn reports() -> Vec<Report> {
     // It can happen that the lock is not intialized, if the library is not
     // loaded with shared_preload_libraries. That leads to a `panic!(...)`.
@@ -41,8 +117,65 @@ pub fn reports() -> Vec<Report> {
     catch_unwind(|| CACHE.share().read()).unwrap_or_default()
 }
 
-pub fn cache_info() -> BufferSummary {
-    catch_unwind(|| CACHE.share().stats()).unwrap_or_default()
+/**
+ Clears the cache. Fails when the library was not loaded with
+ `shared_preload_libraries`, since then there is no cache.
+*/
+pub fn reset() -> anyhow::Result<()> {
+    catch_unwind(|| CACHE.exclusive().reset())
+        .map_err(|_| anyhow!("{} must be loaded with shared_preload_libraries", CRATE))
+}
+
+/**
+ The state of the cache: how full it is, and the span of time it covers.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct CacheInfo {
+    pub buffer: BufferSummary,
+    pub oldest: Option<OffsetDateTime>,
+    pub newest: Option<OffsetDateTime>,
+    /// Reports the cache worker failed to write to the cache.
+    pub write_errors: u64,
+}
+
+impl CacheInfo {
+    pub fn covered(&self) -> Option<Duration> {
+        (self.newest? - self.oldest?).try_into().ok()
+    }
+
+    /**
+     How long reports stay in the cache, at the rate they have been written
+     so far: the time covered, scaled up from the space used to all of it.
+    */
+    pub fn estimated_retention(&self) -> Option<Duration> {
+        let covered = self.covered().filter(|d| !d.is_zero())?;
+        if self.buffer.bytes_used == 0 {
+            return None;
+        }
+        let scale = self.buffer.capacity as f64 / self.buffer.bytes_used as f64;
+        Some(covered.mul_f64(scale))
+    }
+}
+
+pub fn cache_info() -> CacheInfo {
+    let write_errors = worker_status::read()
+        .map(|status| status.write_errors)
+        .unwrap_or_default();
+
+    catch_unwind(|| {
+        let cache = CACHE.share();
+        let (reports, corrupt_records) = cache.scan();
+        CacheInfo {
+            buffer: BufferSummary {
+                corrupt_records,
+                ..cache.stats()
+            },
+            oldest: reports.iter().map(|r| r.at).min(),
+            newest: reports.iter().map(|r| r.at).max(),
+            write_errors,
+        }
+    })
+    .unwrap_or_default()
 }
 
 #[pg_guard]
@@ -52,38 +185,87 @@ pub extern "C" fn cache_worker() {
 
     BackgroundWorker::attach_signal_handlers(flags);
 
+    // A database connection is needed to read alert rules and to deliver
+    // notifications -- listeners in other databases do not see them. Without
+    // one, the worker only caches reports, and does not depend on a database
+    // that may not exist.
+    let database = settings::database();
+    BackgroundWorker::connect_worker_to_spi(database.as_deref(), None);
+    let connected = database.is_some();
+
+    // Two workers may be started at once, by concurrent calls to
+    // `pg_stat_sysinfo_start_worker()`, or by one while the postmaster waits
+    // to restart a worker that exited. Only one of them runs; the other
+    // exits cleanly, so that it is not restarted. This waits until after
+    // connecting, since only then is a worker visible to `claim`.
+    let pid = unsafe { pg_sys::MyProcPid };
+    if !worker_status::claim(pid) {
+        log!("{}: Another cache worker is running; exiting.", CRATE);
+        return;
+    }
+    cpu_baseline::publish_from_this_process();
+
     let name = BackgroundWorker::get_name();
-    let interval = settings::read_or_default().interval;
-    let mut last_config = Instant::now();
+    let settings = settings::read_or_default();
+    let interval = settings.interval;
+    let group_intervals = settings.group_intervals;
+    let mut notify_channel = usable_channel(settings.notify_channel, connected);
+    singleton().configure(&settings.filter);
+    let mut discovery = DiskDiscovery::default();
+    discovery.configure(settings.disk_discovery_interval, settings.watch_mounts);
+    let mut evaluator = connected.then(alerts::Evaluator::default);
+    let mut errors = RateLimitedLog::default();
 
     let mut state: WorkerState = WorkerState::default();
     if let Some(interval) = interval {
-        if state.enable(interval) {
+        if state.enable(interval, &group_intervals) {
             log!(
-                "{}: Initialising {} with interval: {:?}",
+                "{}: Initialising {} with interval: {:?} {:?}",
                 CRATE,
                 name,
-                interval
+                interval,
+                group_intervals
             );
         }
     }
 
-    let mut remaining_time = if state.enabled {
-        state.remaining_time()
-    } else {
-        Duration::MAX
-    };
+    worker_status::update(|status| {
+        status.enabled = state.enabled;
+        status.interval = state.enabled.then_some(state.interval);
+        status.next_run = state.enabled.then(|| next_run(state.remaining_time()));
+    });
 
-    while BackgroundWorker::wait_latch(Some(remaining_time)) {
+    // With no interval set -- the default -- the worker waits here without a
+    // timeout, until a `SIGHUP` or `SIGTERM` sets the latch.
+    while BackgroundWorker::wait_latch(latch_timeout(&state, &discovery)) {
         if BackgroundWorker::sighup_received() {
-            match settings::read_or_default().interval {
+            // The signal handler only sets a flag; the configuration file
+            // has to be read again for the new settings to be visible.
+            unsafe {
+                pg_sys::ProcessConfigFile(pg_sys::GucContext_PGC_SIGHUP);
+            }
+            let settings = settings::read_or_default();
+            singleton().configure(&settings.filter);
+            let channel = usable_channel(settings.notify_channel, connected);
+            if channel != notify_channel {
+                log!(
+                    "{}: Configuring {} to notify on channel: {:?}",
+                    CRATE,
+                    name,
+                    channel
+                );
+                notify_channel = channel;
+            }
+            match settings.interval {
                 Some(interval) => {
-                    if state.enable(interval) {
+                    let groups = &settings.group_intervals;
+                    if state.enable(interval, groups) {
                         log!(
-                            "{}: Configuring {} with interval: {:?}",
+                            "{}: Configuring {} with interval: {:?} {:?}",
                             CRATE,
                             name,
-                            interval
+                            interval,
+                            groups
                         );
                     }
                 }
@@ -93,73 +275,338 @@ pub extern "C" fn cache_worker() {
                     }
                 }
             }
-            let dur = Instant::now().saturating_duration_since(last_config);
-            if dur >= DISK_CACHE_HIATUS {
-                log!(
-                    "{}: Reloading cache of disk metadata in {} after: {:?}",
-                    CRATE,
-                    name,
-                    dur
-                );
-                refresh_collector_disk_listing();
-            }
-            last_config = Instant::now();
+            discovery.configure(settings.disk_discovery_interval, settings.watch_mounts);
         }
 
         if !state.enabled {
-            remaining_time = Duration::MAX;
+            worker_status::update(|status| {
+                status.enabled = false;
+                status.interval = None;
+                status.next_run = None;
+            });
             continue;
         }
 
-        if state.remaining_time() == Duration::ZERO {
+        if let Some(reason) = discovery.due() {
             debug1!(
-                "{}: Writing to cache in {} after: {:?}",
+                "{}: Reloading cache of disk metadata in {}: {}",
                 CRATE,
                 name,
-                Instant::now().saturating_duration_since(state.last_run)
+                reason
+            );
+            refresh_collector_disk_listing(&name);
+            discovery.ran();
+        }
+
+        let due = state.due();
+        if !due.is_empty() {
+            debug1!("{}: Writing to cache in {} for: {:?}", CRATE, name, due);
+            let (late, interval) = state.lateness(&due);
+            let started = Instant::now();
+            write_new_report_to_cache(
+                &due,
+                notify_channel.as_deref(),
+                evaluator.as_mut(),
+                &mut errors,
             );
-            write_new_report_to_cache();
-            state.last_run = Instant::now();
+            state.ran(&due);
+            worker_status::update(|status| {
+                status.last_collection = Some(OffsetDateTime::now_utc());
+                status.last_duration = Some(started.elapsed());
+                status.collections += 1;
+                status.count_lateness(late, interval);
+            });
         }
 
-        remaining_time = state.remaining_time();
+        worker_status::update(|status| {
+            status.enabled = true;
+            status.interval = Some(state.interval);
+            status.next_run = Some(next_run(state.remaining_time()));
+        });
     }
 
     if !state.enabled {
         log!("{}: Shutting down {}", CRATE, name);
     }
+
+    worker_status::update(|status| {
+        if status.pid == pid {
+            status.pid = 0;
+            status.enabled = false;
+            status.next_run = None;
+        }
+    });
+}
+
+/**
+ How long to wait for the next collection or discovery: with no timeout while
+ the worker is disabled, since only a signal can change that.
+*/
+fn latch_timeout(state: &WorkerState, discovery: &DiskDiscovery) -> Option<Duration> {
+    state.enabled.then(|| {
+        let remaining = state.remaining_time().min(discovery.remaining_time());
+        remaining.min(LONGEST_WAIT)
+    })
+}
+
+fn next_run(remaining_time: Duration) -> OffsetDateTime {
+    OffsetDateTime::now_utc() + remaining_time
+}
+
+/**
+ The channel to notify on, if notifications can be sent at all: without a
+ database connection, they can not.
+*/
+fn usable_channel(channel: Option<String>, connected: bool) -> Option<String> {
+    if channel.is_some() && !connected {
+        warning!(
+            "{}: Not notifying on channel {:?}: {}.database is not set.",
+            CRATE,
+            channel,
+            CRATE
+        );
+        return None;
+    }
+    channel
+}
+
+fn write_new_report_to_cache(
+    sources: &[&str],
+    notify_channel: Option<&str>,
+    evaluator: Option<&mut alerts::Evaluator>,
+    errors: &mut RateLimitedLog,
+) {
+    let report = singleton().report_sources(sources);
+    let payload = notify_channel.map(|_| notify::payload(&report));
+
+    // If the rules can not be read, alerts are left as they were, rather
+    // than resolving everything. Without a database, there are no rules.
+    if let Some(evaluator) = evaluator {
+        match alerts::load_rules() {
+            Ok(rules) => {
+                let transitions = evaluator.evaluate(&rules, &report);
+                evaluator.publish(&transitions);
+            }
+            Err(e) => {
+                let message = format!("Failed to load alert rules: {:?}", e);
+                errors.warn(&message);
+                worker_status::update(|status| status.record_error(&message));
+            }
+        }
+    }
+
+    write_to_cache(report, errors);
+
+    // Notify after writing, so that listeners who query the cache in
+    // response see the new report.
+    if let (Some(channel), Some(payload)) = (notify_channel, payload) {
+        notify::send(channel, &payload);
+    }
+}
+
+/**
+ Writes a report to the cache. A report that can not be written -- because
+ it is too large, for example, on a host with hundreds of mounts -- is
+ degraded by dropping samples until it fits, rather than being lost
+ altogether.
+*/
+fn write_to_cache(mut report: Report, errors: &mut RateLimitedLog) {
+    let mut failure = None;
+    let mut dropped = 0;
+    let mut written = false;
+
+    loop {
+        let result = CACHE.exclusive().write(&report);
+        match result {
+            Ok(()) => {
+                written = true;
+                break;
+            }
+            Err(e) => {
+                failure.get_or_insert(e);
+                let before = report.samples.len();
+                if !report.degrade() {
+                    break;
+                }
+                dropped += before - report.samples.len();
+            }
+        }
+    }
+
+    if let Some(e) = failure {
+        let message = if !written {
+            format!("Failed to write report to cache: {:?}", e)
+        } else {
+            format!(
+                "Dropped {} samples to write report to cache: {:?}",
+                dropped, e
+            )
+        };
+        errors.warn(&message);
+        worker_status::update(|status| {
+            status.write_errors += 1;
+            status.record_error(&message);
+        });
+    }
 }
 
-fn write_new_report_to_cache() {
-    let report = singleton().report();
-    CACHE.exclusive().write(report).expect("Full cache?");
+// Repeated failures are logged at most this often.
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/**
+ Logs warnings, suppressing those that come too soon after the last one, so
+ that a failure on every tick does not flood the log.
+*/
+#[derive(Debug, Default)]
+struct RateLimitedLog {
+    last: Option<Instant>,
+    suppressed: u64,
 }
 
-const DISK_CACHE_HIATUS: Duration = Duration::from_secs(100);
+impl RateLimitedLog {
+    fn warn(&mut self, message: &str) {
+        let now = Instant::now();
+        if let Some(last) = self.last {
+            if now.saturating_duration_since(last) < ERROR_LOG_INTERVAL {
+                self.suppressed += 1;
+                return;
+            }
+        }
+
+        if self.suppressed > 0 {
+            warning!(
+                "{}: {} ({} more warnings suppressed)",
+                CRATE,
+                message,
+                self.suppressed
+            );
+        } else {
+            warning!("{}: {}", CRATE, message);
+        }
+        self.last = Some(now);
+        self.suppressed = 0;
+    }
+}
+
+fn refresh_collector_disk_listing(name: &str) {
+    for (source, change) in singleton().discover_new_disks() {
+        if !change.appeared.is_empty() {
+            log!(
+                "{}: {} found new volumes ({}): {:?}",
+                CRATE,
+                name,
+                source,
+                change.appeared
+            );
+        }
+        if !change.disappeared.is_empty() {
+            log!(
+                "{}: {} found volumes removed ({}): {:?}",
+                CRATE,
+                name,
+                source,
+                change.disappeared
+            );
+        }
+    }
+}
+
+/**
+ Schedules reloading of the list of volumes: at a regular interval, and
+ whenever the mount table changes, if it is being watched.
+*/
+#[derive(Debug)]
+struct DiskDiscovery {
+    interval: Option<Duration>,
+    watcher: Option<MountWatcher>,
+    last_run: Instant,
+}
+
+impl DiskDiscovery {
+    fn configure(&mut self, interval: Option<Duration>, watch_mounts: bool) {
+        self.interval = interval;
+        match (watch_mounts, &self.watcher) {
+            (true, None) => {
+                let mut watcher = MountWatcher::default();
+                watcher.changed();
+                self.watcher = Some(watcher);
+            }
+            (false, Some(_)) => self.watcher = None,
+            _ => {}
+        }
+    }
+
+    fn due(&mut self) -> Option<&'static str> {
+        if let Some(watcher) = &mut self.watcher {
+            if watcher.changed() {
+                return Some("mount table changed");
+            }
+        }
+        if self.remaining_time() == Duration::ZERO {
+            return Some("discovery interval passed");
+        }
+        None
+    }
+
+    fn ran(&mut self) {
+        self.last_run = Instant::now();
+    }
 
-fn refresh_collector_disk_listing() {
-    singleton().discover_new_disks();
+    fn remaining_time(&self) -> Duration {
+        match self.interval {
+            Some(interval) => {
+                let passed = Instant::now().saturating_duration_since(self.last_run);
+                interval.saturating_sub(passed)
+            }
+            None => Duration::MAX,
+        }
+    }
 }
 
+impl Default for DiskDiscovery {
+    fn default() -> Self {
+        DiskDiscovery {
+            interval: None,
+            watcher: None,
+            last_run: Instant::now(),
+        }
+    }
+}
+
+// Sources that come due within this long of each other are reported on
+// together, rather than in separate cache entries.
+const SCHEDULING_SLACK: Duration = Duration::from_millis(10);
+
+/**
+ Tracks when each metric source was last reported on. Every source is
+ collected at the default interval unless it has an interval of its own;
+ sources that are not due are left out of the report, so that slowly changing
+ metrics are not repeated in every cache entry.
+*/
 #[derive(Clone, Debug, PartialEq)]
 struct WorkerState {
     interval: Duration,
-    last_run: Instant,
+    group_intervals: Vec<(&'static str, Duration)>,
+    last_runs: Vec<(&'static str, Instant)>,
     enabled: bool,
 }
 
 impl WorkerState {
-    fn enable(&mut self, interval: Duration) -> bool {
+    fn enable(&mut self, interval: Duration, group_intervals: &[(&'static str, Duration)]) -> bool {
         let mut changed = false;
         if !self.enabled {
             self.enabled = true;
-            self.last_run = Instant::now();
+            self.ran(&sources::names());
             changed = true;
         }
         if self.interval != interval {
             self.interval = interval;
             changed = true;
         }
+        if self.group_intervals != group_intervals {
+            self.group_intervals = group_intervals.to_vec();
+            changed = true;
+        }
         changed
     }
 
@@ -172,18 +619,104 @@ impl WorkerState {
         changed
     }
 
+    fn interval_of(&self, group: &str) -> Duration {
+        self.group_intervals
+            .iter()
+            .find(|(name, _)| *name == group)
+            .map(|(_, interval)| *interval)
+            .unwrap_or(self.interval)
+    }
+
+    fn remaining_times(&self) -> impl Iterator<Item = (&'static str, Duration)> + '_ {
+        let now = Instant::now();
+        self.last_runs.iter().map(move |(group, last_run)| {
+            let passed = now.saturating_duration_since(*last_run);
+            (*group, self.interval_of(group).saturating_sub(passed))
+        })
+    }
+
     fn remaining_time(&self) -> Duration {
-        let passed = Instant::now().saturating_duration_since(self.last_run);
-        self.interval.saturating_sub(passed)
+        self.remaining_times()
+            .map(|(_, remaining)| remaining)
+            .min()
+            .unwrap_or(self.interval)
+    }
+
+    /**
+     How long after they were scheduled the given groups ran, for the group
+     that is most late, and that group's interval.
+    */
+    fn lateness(&self, groups: &[&str]) -> (Duration, Duration) {
+        let now = Instant::now();
+        self.last_runs
+            .iter()
+            .filter(|(group, _)| groups.contains(group))
+            .map(|(group, last_run)| {
+                let interval = self.interval_of(group);
+                let passed = now.saturating_duration_since(*last_run);
+                (passed.saturating_sub(interval), interval)
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn due(&self) -> Vec<&'static str> {
+        self.remaining_times()
+            .filter(|(_, remaining)| *remaining <= SCHEDULING_SLACK)
+            .map(|(group, _)| group)
+            .collect()
+    }
+
+    fn ran(&mut self, groups: &[&str]) {
+        let now = Instant::now();
+        for (group, last_run) in &mut self.last_runs {
+            if groups.contains(group) {
+                *last_run = now;
+            }
+        }
     }
 }
 
 impl Default for WorkerState {
     fn default() -> Self {
+        let now = Instant::now();
         WorkerState {
             interval: Duration::default(),
-            last_run: Instant::now(),
+            group_intervals: vec![],
+            last_runs: sources::names().into_iter().map(|g| (g, now)).collect(),
             enabled: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{latch_timeout, DiskDiscovery, WorkerState, LONGEST_WAIT};
+
+    #[test]
+    fn waits_without_timeout_while_disabled() {
+        // As when the worker starts with `pg_stat_sysinfo.interval` unset.
+        let state = WorkerState::default();
+        let discovery = DiskDiscovery::default();
+        assert_eq!(latch_timeout(&state, &discovery), None);
+    }
+
+    #[test]
+    fn waits_at_most_the_longest_wait() {
+        let mut state = WorkerState::default();
+        let mut discovery = DiskDiscovery::default();
+        state.enable(Duration::MAX, &[]);
+        assert_eq!(latch_timeout(&state, &discovery), Some(LONGEST_WAIT));
+        assert!(i32::try_from(LONGEST_WAIT.as_millis()).is_ok());
+
+        state.enable(Duration::from_secs(10), &[]);
+        let timeout = latch_timeout(&state, &discovery).unwrap();
+        assert!(timeout <= Duration::from_secs(10));
+
+        discovery.configure(Some(Duration::from_secs(1)), false);
+        let timeout = latch_timeout(&state, &discovery).unwrap();
+        assert!(timeout <= Duration::from_secs(1));
+    }
+}
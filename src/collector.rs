@@ -1,13 +1,22 @@
+use std::any::Any;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use lazy_static::lazy_static;
 use parking_lot::{Mutex, MutexGuard};
+use pgrx::pg_sys::panic::CaughtError;
+use pgrx::*;
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use sysinfo::{CpuExt, DiskExt, SystemExt};
+use serde_json::{Map, Value};
 use time::OffsetDateTime;
 
+use crate::compact;
+use crate::crate_info::CRATE;
+use crate::filter::Filter;
+use crate::shmem_ring_buffer::Versioned;
+use crate::sources::{self, Discovery, MetricInfo, MetricSource};
+
 lazy_static! {
     static ref SINGLETON: Mutex<Collector> = Mutex::new(Collector::new());
 }
@@ -19,218 +28,462 @@ pub fn singleton() -> MutexGuard<'static, Collector> {
 }
 
 /**
- The system report as Postgres-friendly types. Note that all memory and disk
- sizes are stored as `f64`, which allows for exact representation of up to
- 8192 terabytes.
+ The system report as Postgres-friendly types: samples from every source,
+ taken at the same time.
 */
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Report {
-    pub load: Load,
     pub at: time::OffsetDateTime,
-    pub cpu_usage: f64,
-    pub memory: Memory,
-    pub swap: Memory,
-    pub volumes: Vec<VolumeInfo>,
+    pub samples: Vec<Sample>,
 }
 
 impl Report {
     pub fn rows(&self) -> Vec<(String, Value, OffsetDateTime, f64)> {
-        report_rows(self)
+        self.samples
+            .iter()
+            .map(|s| (s.metric.clone(), s.dimensions_json(), self.at, s.value))
+            .collect()
+    }
+
+    /**
+     Drops half of the samples with dimensions -- those for each volume, for
+     example -- starting from the end of the report, so that an oversized
+     report can be made to fit in the cache. Returns false when there is
+     nothing left to drop.
+    */
+    pub fn degrade(&mut self) -> bool {
+        let dimensioned = self
+            .samples
+            .iter()
+            .filter(|s| !s.dimensions.is_empty())
+            .count();
+        if dimensioned == 0 {
+            return false;
+        }
+
+        let mut to_drop = (dimensioned + 1) / 2;
+        let mut i = self.samples.len();
+        while to_drop > 0 && i > 0 {
+            i -= 1;
+            if !self.samples[i].dimensions.is_empty() {
+                self.samples.remove(i);
+                to_drop -= 1;
+            }
+        }
+
+        true
     }
 }
 
-fn report_rows(r: &Report) -> Vec<(String, Value, OffsetDateTime, f64)> {
-    let ownerize = |v: Vec<(&str, &Value, OffsetDateTime, f64)>| -> Vec<_> {
-        v.into_iter()
-            .map(|(a, b, c, d)| (String::from(a), b.clone(), c, d))
-            .collect()
-    };
-    let empty = json!({});
-    let duration1m = json!({"duration": "1m"});
-    let duration5m = json!({"duration": "5m"});
-    let duration15m = json!({"duration": "15m"});
-    let mut result = ownerize(vec![
-        ("load_average", &duration1m, r.at, r.load.min1),
-        ("load_average", &duration5m, r.at, r.load.min5),
-        ("load_average", &duration15m, r.at, r.load.min15),
-        ("cpu_usage", &empty, r.at, r.cpu_usage),
-        ("memory_usage", &empty, r.at, r.memory.usage),
-        ("memory_size", &empty, r.at, r.memory.size),
-        ("memory_available", &empty, r.at, r.memory.available),
-        ("swap_usage", &empty, r.at, r.swap.usage),
-        ("swap_size", &empty, r.at, r.swap.size),
-        ("swap_available", &empty, r.at, r.swap.available),
-    ]);
-
-    for vol in &r.volumes {
-        let dims = json!({ "fs": vol.name });
-        result.append(&mut ownerize(vec![
-            ("disk_usage", &dims, r.at, vol.usage),
-            ("disk_size", &dims, r.at, vol.size),
-            ("disk_available", &dims, r.at, vol.available),
-        ]));
-    }
-
-    result
+/**
+ The layouts of `Report` in the cache, by schema version:
+
+   1. Fixed fields for load, CPU, memory, swap and volumes.
+   2. A list of samples, from any number of sources.
+*/
+impl Versioned for Report {
+    const SCHEMA: u16 = 2;
+
+    fn decode(schema: u16, payload: &[u8]) -> anyhow::Result<Self> {
+        match schema {
+            2 => Ok(serde_bare::from_slice(payload)?),
+            1 => Ok(serde_bare::from_slice::<schema1::Report>(payload)?.into()),
+            v => Err(anyhow::anyhow!("Unknown report schema {}.", v)),
+        }
+    }
+
+    fn encode_block(reports: &[Self]) -> Option<Vec<u8>> {
+        Some(compact::encode(reports))
+    }
+
+    fn decode_block(schema: u16, payload: &[u8]) -> anyhow::Result<Vec<Self>> {
+        match schema {
+            2 => compact::decode(payload),
+            v => Err(anyhow::anyhow!("Unknown report schema {}.", v)),
+        }
+    }
+}
+
+/**
+ A single measurement. Dimensions distinguish measurements of the same
+ metric, like the mount point of a volume.
+*/
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Sample {
+    pub metric: String,
+    pub dimensions: Vec<(String, String)>,
+    pub value: f64,
+}
+
+impl Sample {
+    pub fn new(metric: &str, dimensions: &[(&str, &str)], value: f64) -> Self {
+        let dimensions = dimensions
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        Sample {
+            metric: metric.to_string(),
+            dimensions,
+            value,
+        }
+    }
+
+    pub fn dimensions_json(&self) -> Value {
+        let map: Map<String, Value> = self
+            .dimensions
+            .iter()
+            .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+            .collect();
+        Value::Object(map)
+    }
 }
 
 /**
- The collector manages system caches and reporting.
+ The collector manages the metric sources and reporting.
 */
 pub struct Collector {
-    client: sysinfo::System,
+    sources: Vec<Registered>,
+    filter: Filter,
+    initialized: bool,
+}
+
+struct Registered {
+    source: Box<dyn MetricSource>,
     last_refresh: Option<Instant>,
+    healthy: bool,
+    enabled: bool,
+    selected: bool,
+}
+
+impl Registered {
+    fn active(&self) -> bool {
+        self.enabled && self.selected
+    }
 }
 
 impl Collector {
     pub fn new() -> Self {
+        let sources = sources::registry()
+            .into_iter()
+            .map(|source| Registered {
+                source,
+                last_refresh: None,
+                healthy: false,
+                enabled: true,
+                selected: true,
+            })
+            .collect();
+
         Collector {
-            client: sysinfo::System::new(),
-            last_refresh: None,
+            sources,
+            filter: Filter::default(),
+            initialized: false,
+        }
+    }
+
+    /**
+     Selects the metrics to report. Sources for which no metrics are
+     selected are disabled and not refreshed at all.
+    */
+    pub fn configure(&mut self, filter: &Filter) {
+        if &self.filter == filter {
+            return;
+        }
+        for r in &mut self.sources {
+            let metrics = r.source.metrics();
+            r.enabled = metrics.iter().any(|m| filter.metric(m.name));
+            r.source.configure(filter);
         }
+        self.filter = filter.clone();
     }
 
     pub fn report(&mut self) -> Report {
-        if self.last_refresh.is_none() {
+        let names: Vec<_> = self.sources.iter().map(|r| r.source.name()).collect();
+        self.report_sources(&names)
+    }
+
+    /**
+     Reports on only some sources, by name. This allows sources to be
+     collected at different intervals.
+    */
+    pub fn report_sources(&mut self, names: &[&str]) -> Report {
+        if !self.initialized {
             self.cache_initialization();
         }
 
+        for r in &mut self.sources {
+            r.selected = names.contains(&r.source.name());
+        }
+
         self.refresh();
 
         let at = OffsetDateTime::now_utc();
-
-        let load_average = self.client.load_average();
-
-        let disks = self.client.disks();
-        let volumes = disks.iter().map(VolumeInfo::from).collect();
-
-        let memory = Memory::from_total_and_available(
-            self.client.total_memory(),
-            // Why this stat: free means memory that is not used for anything,
-            // whereas available means memory that can be allocated, including
-            // by flushing cache or buffers. This seems to be more indicative
-            // of the real system state.
-            self.client.available_memory(),
-        );
-        let swap = Memory::from_total_and_available(
-            self.client.total_swap(),
-            // NB: No `available_swap` statistic.
-            self.client.free_swap(),
-        );
-
-        let cpu_usage = self.client.global_cpu_info().cpu_usage() as f64;
-
-        Report {
-            load: Load {
-                min1: load_average.one,
-                min5: load_average.five,
-                min15: load_average.fifteen,
-            },
-            at,
-            cpu_usage,
-            memory,
-            swap,
-            volumes,
+        let mut samples = vec![];
+
+        for r in self.sources.iter().filter(|r| r.active() && r.healthy) {
+            let source = &r.source;
+            let emitted = isolate(source.name(), || {
+                let mut emitted = vec![];
+                source.emit(&mut emitted);
+                Ok(emitted)
+            });
+            samples.extend(emitted.unwrap_or_default());
         }
+        samples.retain(|s| self.filter.metric(&s.metric));
+
+        Report { at, samples }
     }
 
     // Anything that needs to be run before taking the first real measurements.
     pub fn cache_initialization(&mut self) {
-        self.discover_new_disks();
-        self.client.refresh_cpu();
-        self.last_refresh = Some(Instant::now());
+        for r in &mut self.sources {
+            let source = &mut r.source;
+            r.healthy = isolate(source.name(), || source.initialize()).is_some();
+            r.last_refresh = Some(Instant::now());
+        }
+        self.initialized = true;
     }
 
     pub fn is_initialized(&self) -> bool {
-        self.last_refresh.is_some()
+        self.initialized
     }
 
     fn refresh(&mut self) {
-        if let Some(dur) = self.cpu_safe_sleep() {
-            thread::sleep(dur);
+        if let Some(dur) = self.warm_up_remaining() {
+            sleep_interruptibly(dur);
         }
-        // All of these seem to be implemented in a way that is relatively
-        // fault tolerant. For example, if a disk was removed and can not be
-        // refreshed, it simply won't be updated.
-        self.client.refresh_cpu();
-        self.client.refresh_disks();
-        self.client.refresh_memory();
 
-        self.last_refresh = Some(Instant::now());
+        for r in self.sources.iter_mut().filter(|r| r.active()) {
+            let source = &mut r.source;
+            r.healthy = isolate(source.name(), || source.refresh()).is_some();
+            r.last_refresh = Some(Instant::now());
+        }
     }
 
-    fn cpu_safe_sleep(&self) -> Option<Duration> {
+    // The longest any source must still wait for its measurements to be
+    // meaningful.
+    fn warm_up_remaining(&self) -> Option<Duration> {
         let zero = Duration::new(0, 0);
         let now = Instant::now();
-        let passed = self.last_refresh.map(|t| now - t).unwrap_or(zero);
 
-        sysinfo::System::MINIMUM_CPU_UPDATE_INTERVAL.checked_sub(passed)
+        self.sources
+            .iter()
+            .filter(|r| r.active())
+            .filter_map(|r| {
+                let passed = r.last_refresh.map(|t| now - t).unwrap_or(zero);
+                r.source.warm_up().checked_sub(passed)
+            })
+            .max()
     }
 
     // This should be run once in an awhile, or due to device events or
     // something of that nature.
-    pub fn discover_new_disks(&mut self) {
-        self.client.refresh_disks_list();
+    pub fn discover_new_disks(&mut self) -> Vec<(&'static str, Discovery)> {
+        let mut changes = vec![];
+        for r in &mut self.sources {
+            let source = &mut r.source;
+            let name = source.name();
+            if let Some(discovery) = isolate(name, || Ok(source.discover())) {
+                if !discovery.is_empty() {
+                    changes.push((name, discovery));
+                }
+            }
+        }
+        changes
     }
-}
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct VolumeInfo {
-    pub name: String,
-    pub size: f64,
-    pub available: f64,
-    pub usage: f64,
+    pub fn metrics(&self) -> Vec<(&'static str, MetricInfo)> {
+        self.sources
+            .iter()
+            .flat_map(|r| {
+                let name = r.source.name();
+                r.source.metrics().iter().map(move |m| (name, *m))
+            })
+            .collect()
+    }
 }
 
-impl From<&sysinfo::Disk> for VolumeInfo {
-    fn from(disk: &sysinfo::Disk) -> Self {
-        let name = disk.mount_point().to_string_lossy().into_owned();
-        let size = disk.total_space() as f64;
-        let available = disk.available_space() as f64;
-        let usage = usage_percent(size, available);
+/**
+ Sleeps, but wakes up regularly to check for a query cancel or termination.
+*/
+pub fn sleep_interruptibly(duration: Duration) {
+    const CHECK_INTERVAL: Duration = Duration::from_millis(10);
+    let until = Instant::now() + duration;
 
-        VolumeInfo {
-            name,
-            size,
-            available,
-            usage,
+    loop {
+        pg_sys::check_for_interrupts!();
+        let now = Instant::now();
+        if now >= until {
+            return;
         }
+        thread::sleep((until - now).min(CHECK_INTERVAL));
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct Load {
-    pub min1: f64,
-    pub min5: f64,
-    pub min15: f64,
+/**
+ Runs a source's method, logging and discarding errors and panics so that
+ one misbehaving source can not take down the others. Errors raised by
+ Postgres -- like a query cancel, noticed while a source waits -- are passed
+ on, so that they abort the query as they should.
+*/
+fn isolate<R>(source: &str, f: impl FnOnce() -> anyhow::Result<R>) -> Option<R> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(result)) => Some(result),
+        Ok(Err(e)) => {
+            warning!("{}: Failure in source {}: {:?}", CRATE, source, e);
+            None
+        }
+        Err(panic) if is_postgres_error(&*panic) => resume_unwind(panic),
+        Err(_) => {
+            warning!("{}: Panic in source {}.", CRATE, source);
+            None
+        }
+    }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct Memory {
-    pub size: f64,
-    pub available: f64,
-    pub usage: f64,
+fn is_postgres_error(panic: &(dyn Any + Send)) -> bool {
+    matches!(
+        panic.downcast_ref::<CaughtError>(),
+        Some(CaughtError::PostgresError(_))
+    )
 }
 
-impl Memory {
-    fn from_total_and_available(size: u64, available: u64) -> Self {
-        let size = size as f64;
-        let available = available as f64;
-        let usage = usage_percent(size, available);
+/**
+ The first layout of `Report`, with a field for each metric.
+*/
+mod schema1 {
+    use serde::{Deserialize, Serialize};
+
+    use super::Sample;
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Report {
+        pub load: Load,
+        pub at: time::OffsetDateTime,
+        pub cpu_usage: f64,
+        pub memory: Memory,
+        pub swap: Memory,
+        pub volumes: Vec<VolumeInfo>,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Load {
+        pub min1: f64,
+        pub min5: f64,
+        pub min15: f64,
+    }
 
-        Memory {
-            size,
-            available,
-            usage,
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Memory {
+        pub size: f64,
+        pub available: f64,
+        pub usage: f64,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct VolumeInfo {
+        pub name: String,
+        pub size: f64,
+        pub available: f64,
+        pub usage: f64,
+    }
+
+    impl From<Report> for super::Report {
+        fn from(r: Report) -> Self {
+            let mut samples = vec![
+                Sample::new("load_average", &[("duration", "1m")], r.load.min1),
+                Sample::new("load_average", &[("duration", "5m")], r.load.min5),
+                Sample::new("load_average", &[("duration", "15m")], r.load.min15),
+                Sample::new("cpu_usage", &[], r.cpu_usage),
+                Sample::new("memory_usage", &[], r.memory.usage),
+                Sample::new("memory_size", &[], r.memory.size),
+                Sample::new("memory_available", &[], r.memory.available),
+                Sample::new("swap_usage", &[], r.swap.usage),
+                Sample::new("swap_size", &[], r.swap.size),
+                Sample::new("swap_available", &[], r.swap.available),
+            ];
+
+            for vol in &r.volumes {
+                let dims = [("fs", vol.name.as_str())];
+                samples.extend([
+                    Sample::new("disk_usage", &dims, vol.usage),
+                    Sample::new("disk_size", &dims, vol.size),
+                    Sample::new("disk_available", &dims, vol.available),
+                ]);
+            }
+
+            super::Report { at: r.at, samples }
         }
     }
 }
 
-fn usage_percent(size: f64, available: f64) -> f64 {
-    100.0
-        * if size > 0.0 {
-            1.0 - available / size
-        } else {
-            0.0
-        }
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+
+    use super::{schema1, Report, Sample};
+    use crate::shmem_ring_buffer::ShmemBackedSerdeRingBuffer;
+
+    #[test]
+    fn reads_reports_written_with_schema1() {
+        let at = OffsetDateTime::from_unix_timestamp(1_674_000_000).unwrap();
+        let memory = schema1::Memory {
+            size: 16e9,
+            available: 4e9,
+            usage: 75.0,
+        };
+        let old = schema1::Report {
+            load: schema1::Load {
+                min1: 1.5,
+                min5: 1.0,
+                min15: 0.5,
+            },
+            at,
+            cpu_usage: 12.5,
+            memory: memory.clone(),
+            swap: memory,
+            volumes: vec![schema1::VolumeInfo {
+                name: "/".into(),
+                size: 100e9,
+                available: 40e9,
+                usage: 60.0,
+            }],
+        };
+        let new = Report {
+            at,
+            samples: vec![Sample::new("cpu_usage", &[], 25.0)],
+        };
+
+        let mut buffer = ShmemBackedSerdeRingBuffer::<4096, 2, Report>::default();
+        buffer.write_versioned(1, &serde_bare::to_vec(&old).unwrap());
+        buffer.write(&new).unwrap();
+
+        let (reports, corrupt) = buffer.scan();
+        assert_eq!(corrupt, 0);
+        assert_eq!(reports.len(), 2);
+
+        let upgraded = &reports[0];
+        assert_eq!(upgraded.at, at);
+        assert_eq!(upgraded.samples.len(), 13);
+        let value = |metric: &str, dims: &[(&str, &str)]| {
+            upgraded
+                .samples
+                .iter()
+                .find(|s| {
+                    s.metric == metric
+                        && s.dimensions
+                            .iter()
+                            .map(|(k, v)| (k.as_str(), v.as_str()))
+                            .eq(dims.iter().copied())
+                })
+                .map(|s| s.value)
+        };
+        assert_eq!(value("load_average", &[("duration", "5m")]), Some(1.0));
+        assert_eq!(value("cpu_usage", &[]), Some(12.5));
+        assert_eq!(value("memory_available", &[]), Some(4e9));
+        assert_eq!(value("disk_usage", &[("fs", "/")]), Some(60.0));
+
+        assert_eq!(reports[1].samples[0].value, 25.0);
+    }
 }
@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use time::OffsetDateTime;
+
+use crate::collector::{Report, Sample};
+
+/*
+  A compact encoding for a run of reports, used to pack the reports in a full
+  cache page into a single record. Successive reports are very similar: the
+  same metrics, with the same dimensions, and values that change little or
+  not at all. So:
+
+  * Metric names and dimension keys and values are interned: each appears
+    once, in a string table.
+  * Each distinct metric and set of dimensions -- a series -- appears once,
+    in a series table, as indexes into the string table.
+  * Each report lists the series it has samples for, unless the list is the
+    same as that of the previous report.
+  * Timestamps are stored as the difference between successive differences,
+    which is nearly zero when reports are taken at a regular interval.
+  * Values are XORed with the previous value in their series and only the
+    meaningful bits are stored, as in Facebook's Gorilla.
+
+    block   := strings series reports values
+    strings := varint(count) (varint(len) bytes)*
+    series  := varint(count) (varint(metric) varint(dims) (varint(k) varint(v))*)*
+    reports := varint(count) (zigzag(timestamp) varint(list))*
+    list    := 0                            -- same series as the last report
+             | varint(count + 1) varint(series)*
+    values  := bits, to the end of the block
+*/
+
+type SeriesKey = (usize, Vec<(usize, usize)>);
+
+pub fn encode(reports: &[Report]) -> Vec<u8> {
+    let mut strings = Interner::default();
+    let mut series: Interner<SeriesKey> = Interner::default();
+    let mut lists: Vec<Vec<usize>> = vec![];
+
+    for report in reports {
+        let list = report
+            .samples
+            .iter()
+            .map(|s| {
+                let metric = strings.intern(&s.metric);
+                let dims = s
+                    .dimensions
+                    .iter()
+                    .map(|(k, v)| (strings.intern(k), strings.intern(v)))
+                    .collect();
+                series.intern(&(metric, dims))
+            })
+            .collect();
+        lists.push(list);
+    }
+
+    let mut out = vec![];
+
+    varint(&mut out, strings.items.len() as u64);
+    for s in &strings.items {
+        varint(&mut out, s.len() as u64);
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    varint(&mut out, series.items.len() as u64);
+    for (metric, dims) in &series.items {
+        varint(&mut out, *metric as u64);
+        varint(&mut out, dims.len() as u64);
+        for (k, v) in dims {
+            varint(&mut out, *k as u64);
+            varint(&mut out, *v as u64);
+        }
+    }
+
+    varint(&mut out, reports.len() as u64);
+    let mut times = Deltas::default();
+    let mut previous: Option<&Vec<usize>> = None;
+    for (report, list) in reports.iter().zip(&lists) {
+        let nanos = report.at.unix_timestamp_nanos() as i64;
+        varint(&mut out, zigzag(times.encode(nanos)));
+        if previous == Some(list) {
+            varint(&mut out, 0);
+        } else {
+            varint(&mut out, list.len() as u64 + 1);
+            for id in list {
+                varint(&mut out, *id as u64);
+            }
+        }
+        previous = Some(list);
+    }
+
+    let mut bits = BitWriter::default();
+    let mut xors = vec![Xor::default(); series.items.len()];
+    for (report, list) in reports.iter().zip(&lists) {
+        for (sample, id) in report.samples.iter().zip(list) {
+            xors[*id].encode(&mut bits, sample.value);
+        }
+    }
+    out.extend_from_slice(&bits.finish());
+
+    out
+}
+
+pub fn decode(block: &[u8]) -> anyhow::Result<Vec<Report>> {
+    let mut input = Input {
+        bytes: block,
+        pos: 0,
+    };
+
+    let mut strings = vec![];
+    for _ in 0..input.count()? {
+        let len = input.count()?;
+        let bytes = input.take(len)?;
+        strings.push(String::from_utf8(bytes.to_vec())?);
+    }
+    let string = |i: u64| -> anyhow::Result<&String> {
+        strings
+            .get(i as usize)
+            .ok_or_else(|| anyhow!("String {} is not in the table.", i))
+    };
+
+    let mut series = vec![];
+    for _ in 0..input.count()? {
+        let metric = string(input.varint()?)?;
+        let mut dims = vec![];
+        for _ in 0..input.count()? {
+            let k = string(input.varint()?)?;
+            let v = string(input.varint()?)?;
+            dims.push((k.clone(), v.clone()));
+        }
+        series.push((metric.clone(), dims));
+    }
+
+    let mut headers = vec![];
+    let mut times = Deltas::default();
+    let mut list: Vec<usize> = vec![];
+    for _ in 0..input.count()? {
+        let nanos = times.decode(unzigzag(input.varint()?));
+        let at = OffsetDateTime::from_unix_timestamp_nanos(nanos as i128)?;
+        let len = input.count()?;
+        if len > 0 {
+            list.clear();
+            for _ in 0..len - 1 {
+                let id = input.varint()? as usize;
+                if id >= series.len() {
+                    return Err(anyhow!("Series {} is not in the table.", id));
+                }
+                list.push(id);
+            }
+        }
+        headers.push((at, list.clone()));
+    }
+
+    let mut bits = BitReader::new(&block[input.pos..]);
+    let mut xors = vec![Xor::default(); series.len()];
+    let mut reports = vec![];
+    for (at, list) in headers {
+        let mut samples = Vec::with_capacity(list.len());
+        for id in list {
+            let (metric, dimensions) = &series[id];
+            samples.push(Sample {
+                metric: metric.clone(),
+                dimensions: dimensions.clone(),
+                value: xors[id].decode(&mut bits)?,
+            });
+        }
+        reports.push(Report { at, samples });
+    }
+
+    Ok(reports)
+}
+
+#[derive(Default)]
+struct Interner<T> {
+    items: Vec<T>,
+    index: HashMap<T, usize>,
+}
+
+impl<T: Clone + Eq + std::hash::Hash> Interner<T> {
+    fn intern<Q>(&mut self, item: &Q) -> usize
+    where
+        Q: ToOwned<Owned = T> + ?Sized,
+    {
+        let item = item.to_owned();
+        if let Some(&i) = self.index.get(&item) {
+            return i;
+        }
+        self.items.push(item.clone());
+        self.index.insert(item, self.items.len() - 1);
+        self.items.len() - 1
+    }
+}
+
+/**
+ Delta-of-delta encoding of timestamps.
+*/
+#[derive(Default)]
+struct Deltas {
+    last: Option<i64>,
+    delta: i64,
+}
+
+impl Deltas {
+    fn encode(&mut self, t: i64) -> i64 {
+        let encoded = match self.last {
+            None => t,
+            Some(last) => {
+                let delta = t.wrapping_sub(last);
+                let dod = delta.wrapping_sub(self.delta);
+                self.delta = delta;
+                dod
+            }
+        };
+        self.last = Some(t);
+        encoded
+    }
+
+    fn decode(&mut self, encoded: i64) -> i64 {
+        let t = match self.last {
+            None => encoded,
+            Some(last) => {
+                self.delta = self.delta.wrapping_add(encoded);
+                last.wrapping_add(self.delta)
+            }
+        };
+        self.last = Some(t);
+        t
+    }
+}
+
+/**
+ Gorilla's XOR encoding of the values of one series. A value equal to the
+ previous one takes a single bit. Otherwise, the XOR of the two is stored
+ without its leading and trailing zeros -- reusing the previous window of
+ meaningful bits when the XOR fits in it.
+*/
+#[derive(Clone, Default)]
+struct Xor {
+    last: u64,
+    // Leading zeros and length of the meaningful bits, once there are any.
+    window: Option<(u32, u32)>,
+}
+
+impl Xor {
+    fn encode(&mut self, bits: &mut BitWriter, value: f64) {
+        let xor = value.to_bits() ^ self.last;
+        self.last = value.to_bits();
+
+        if xor == 0 {
+            bits.write(0, 1);
+            return;
+        }
+        bits.write(1, 1);
+
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+        match self.window {
+            Some((l, len)) if leading >= l && trailing >= 64 - l - len => {
+                bits.write(0, 1);
+                bits.write(xor >> (64 - l - len), len);
+            }
+            _ => {
+                let len = 64 - leading - trailing;
+                bits.write(1, 1);
+                bits.write(leading as u64, 5);
+                bits.write((len - 1) as u64, 6);
+                bits.write(xor >> trailing, len);
+                self.window = Some((leading, len));
+            }
+        }
+    }
+
+    fn decode(&mut self, bits: &mut BitReader) -> anyhow::Result<f64> {
+        if bits.read(1)? == 1 {
+            let (leading, len) = match (bits.read(1)?, self.window) {
+                (0, Some(window)) => window,
+                (0, None) => return Err(anyhow!("No previous window of bits.")),
+                _ => {
+                    let leading = bits.read(5)? as u32;
+                    let len = bits.read(6)? as u32 + 1;
+                    if leading + len > 64 {
+                        return Err(anyhow!("Window of bits is too wide."));
+                    }
+                    self.window = Some((leading, len));
+                    (leading, len)
+                }
+            };
+            self.last ^= bits.read(len)? << (64 - leading - len);
+        }
+        Ok(f64::from_bits(self.last))
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u32,
+}
+
+impl BitWriter {
+    // Writes the low `n` bits of `value`, most significant first.
+    fn write(&mut self, value: u64, n: u32) {
+        for i in (0..n).rev() {
+            if self.used as usize == self.bytes.len() * 8 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().expect("Just pushed.") |= bit << (7 - self.used % 8);
+            self.used += 1;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, pos: 0 }
+    }
+
+    fn read(&mut self, n: u32) -> anyhow::Result<u64> {
+        let mut value = 0;
+        for _ in 0..n {
+            let byte = self
+                .bytes
+                .get(self.pos / 8)
+                .ok_or_else(|| anyhow!("Ran out of bits."))?;
+            value = (value << 1) | ((byte >> (7 - self.pos % 8)) & 1) as u64;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+}
+
+struct Input<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Input<'a> {
+    fn varint(&mut self) -> anyhow::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self
+                .bytes
+                .get(self.pos)
+                .ok_or_else(|| anyhow!("Ran out of bytes."))?;
+            self.pos += 1;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(anyhow!("Varint is too long."))
+    }
+
+    // A count of things that follow, each taking at least a byte -- so it can
+    // be checked against what is left.
+    fn count(&mut self) -> anyhow::Result<usize> {
+        let n = self.varint()?;
+        if n > (self.bytes.len() - self.pos) as u64 + 1 {
+            return Err(anyhow!("Count {} is larger than the block.", n));
+        }
+        Ok(n as usize)
+    }
+
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| anyhow!("Ran out of bytes."))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    ((n >> 1) as i64) ^ -((n & 1) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+
+    use super::{decode, encode};
+    use crate::collector::{Report, Sample};
+
+    fn report(seconds: i64, samples: Vec<Sample>) -> Report {
+        let at = OffsetDateTime::from_unix_timestamp(1_674_000_000 + seconds).unwrap();
+        Report { at, samples }
+    }
+
+    // Compares values bit for bit, so that NaN and the sign of zero count.
+    fn assert_round_trip(reports: &[Report]) {
+        let decoded = decode(&encode(reports)).unwrap();
+        assert_eq!(decoded.len(), reports.len());
+        for (decoded, report) in decoded.iter().zip(reports) {
+            assert_eq!(decoded.at, report.at);
+            assert_eq!(decoded.samples.len(), report.samples.len());
+            for (d, s) in decoded.samples.iter().zip(&report.samples) {
+                assert_eq!(d.metric, s.metric);
+                assert_eq!(d.dimensions, s.dimensions);
+                assert_eq!(d.value.to_bits(), s.value.to_bits());
+            }
+        }
+    }
+
+    fn series(values: &[f64]) -> Vec<Report> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| {
+                let sample = Sample::new("disk_usage", &[("fs", "/")], value);
+                report(i as i64 * 10, vec![sample])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_equal_values() {
+        assert_round_trip(&series(&[0.0, 0.0, 42.5, 42.5, 42.5]));
+    }
+
+    #[test]
+    fn round_trips_nan_and_signed_zero() {
+        assert_round_trip(&series(&[f64::NAN, 0.0, -0.0, 0.0, f64::NAN, -f64::NAN]));
+    }
+
+    #[test]
+    fn round_trips_xor_with_many_leading_zeros() {
+        // The XOR of these is 1, with 63 leading zeros -- more than the five
+        // bits of the window can hold.
+        let a = 1.0f64;
+        let b = f64::from_bits(a.to_bits() ^ 1);
+        let c = f64::from_bits(a.to_bits() ^ 3);
+        assert!((a.to_bits() ^ b.to_bits()).leading_zeros() > 31);
+        assert_round_trip(&series(&[a, b, c, a]));
+    }
+
+    #[test]
+    fn round_trips_full_width_window() {
+        // The XOR of these has both its top and bottom bits set, so the
+        // window is all 64 bits; the value after it reuses that window.
+        let a = f64::from_bits(0x0000_0000_0000_0000);
+        let b = f64::from_bits(0x8000_0000_0000_0001);
+        let c = f64::from_bits(0x7FF0_0000_0000_0003);
+        assert_round_trip(&series(&[a, b, c, b]));
+    }
+
+    #[test]
+    fn round_trips_report_without_samples() {
+        let reports = vec![
+            report(0, vec![]),
+            report(10, vec![Sample::new("cpu_usage", &[], 12.5)]),
+            report(20, vec![]),
+            report(30, vec![]),
+        ];
+        assert_round_trip(&reports);
+    }
+
+    #[test]
+    fn round_trips_changing_series() {
+        let root = [("fs", "/")];
+        let data = [("fs", "/data")];
+        let reports = vec![
+            report(
+                0,
+                vec![
+                    Sample::new("cpu_usage", &[], 10.0),
+                    Sample::new("disk_usage", &root, 50.0),
+                ],
+            ),
+            report(
+                10,
+                vec![
+                    Sample::new("cpu_usage", &[], 11.0),
+                    Sample::new("disk_usage", &root, 50.0),
+                ],
+            ),
+            report(
+                20,
+                vec![
+                    Sample::new("disk_usage", &data, 70.0),
+                    Sample::new("cpu_usage", &[], 12.0),
+                    Sample::new("disk_usage", &root, 51.0),
+                ],
+            ),
+            report(25, vec![Sample::new("disk_usage", &root, 51.0)]),
+        ];
+        assert_round_trip(&reports);
+    }
+
+    #[test]
+    fn truncated_block_is_an_error() {
+        let reports = vec![
+            report(0, vec![Sample::new("cpu_usage", &[], 10.0)]),
+            report(10, vec![Sample::new("cpu_usage", &[], 12.5)]),
+        ];
+        let block = encode(&reports);
+        for len in 0..block.len() {
+            assert!(decode(&block[..len]).is_err(), "{} bytes decoded", len);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs;
+use std::panic::catch_unwind;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use pgrx::*;
+use time::OffsetDateTime;
+
+/*
+  CPU usage is the share of CPU time spent working between two measurements,
+  so a backend that measures it for the first time must wait before it can
+  report anything meaningful. With a snapshot of the CPU time counters in
+  shared memory -- taken by the cache worker, or by another backend -- there
+  is no need to wait: the usage is measured against the snapshot.
+*/
+
+static BASELINE: PgLwLock<Baseline> = PgLwLock::new();
+
+unsafe impl PGRXSharedMemory for Baseline {}
+
+// Set in the cache worker, which only publishes snapshots. It measures CPU
+// usage over its own interval, not since whatever a backend left behind.
+static PUBLISHER: AtomicBool = AtomicBool::new(false);
+
+/**
+ CPU time counters, summed over all CPUs, in clock ticks.
+*/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuTicks {
+    pub busy: u64,
+    pub total: u64,
+}
+
+impl CpuTicks {
+    /**
+     Reads the counters from the `cpu` line of `/proc/stat`: user, nice,
+     system, idle, iowait, irq, softirq and steal. (Guest time is already
+     counted in user time.)
+    */
+    pub fn read() -> anyhow::Result<Self> {
+        let stat = fs::read_to_string("/proc/stat")?;
+        let line = stat
+            .lines()
+            .find(|line| line.starts_with("cpu "))
+            .ok_or_else(|| anyhow::anyhow!("No cpu line in /proc/stat."))?;
+        let counters = line
+            .split_whitespace()
+            .skip(1)
+            .take(8)
+            .map(|n| n.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        if counters.len() < 5 {
+            return Err(anyhow::anyhow!("Too few counters in /proc/stat."));
+        }
+
+        let total = counters.iter().sum::<u64>();
+        let idle = counters[3] + counters[4];
+        Ok(CpuTicks {
+            busy: total - idle,
+            total,
+        })
+    }
+
+    /**
+     The percentage of CPU time spent working since an earlier reading.
+    */
+    pub fn usage_since(&self, earlier: &CpuTicks) -> Option<f64> {
+        let total = self.total.checked_sub(earlier.total)?;
+        let busy = self.busy.checked_sub(earlier.busy)?;
+        (total > 0).then(|| 100.0 * busy as f64 / total as f64)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Baseline {
+    ticks: Option<CpuTicks>,
+    at: Option<OffsetDateTime>,
+}
+
+impl Baseline {
+    fn age(&self) -> Option<Duration> {
+        let age = OffsetDateTime::now_utc() - self.at?;
+        age.try_into().ok()
+    }
+}
+
+pub fn init() {
+    pg_shmem_init!(BASELINE);
+}
+
+pub fn publish_from_this_process() {
+    PUBLISHER.store(true, Ordering::Relaxed);
+}
+
+pub fn is_publisher() -> bool {
+    PUBLISHER.load(Ordering::Relaxed)
+}
+
+/**
+ How long ago the snapshot was taken. `None` when there is no snapshot, or
+ when the library was not loaded with `shared_preload_libraries`.
+*/
+pub fn age() -> Option<Duration> {
+    // See `cache_worker::reports()` for why we catch the panic.
+    catch_unwind(|| BASELINE.share().age()).ok().flatten()
+}
+
+pub fn publish(ticks: CpuTicks) {
+    let _ = catch_unwind(|| {
+        *BASELINE.exclusive() = Baseline {
+            ticks: Some(ticks),
+            at: Some(OffsetDateTime::now_utc()),
+        }
+    });
+}
+
+/**
+ Swaps the snapshot for a new one, returning the old one if it is at least
+ `min_age` old. A younger snapshot is left in place, so that callers in quick
+ succession do not each push it forward and leave the next one waiting.
+*/
+pub fn take(ticks: CpuTicks, min_age: Duration) -> Option<CpuTicks> {
+    catch_unwind(|| {
+        let mut baseline = BASELINE.exclusive();
+        let old = match baseline.age() {
+            Some(age) if age < min_age => return None,
+            _ => baseline.ticks,
+        };
+        *baseline = Baseline {
+            ticks: Some(ticks),
+            at: Some(OffsetDateTime::now_utc()),
+        };
+        old
+    })
+    .ok()
+    .flatten()
+}
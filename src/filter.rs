@@ -0,0 +1,172 @@
+/**
+ Which metrics and volumes to report on, as configured by
+ `pg_stat_sysinfo.metrics`, `pg_stat_sysinfo.volumes_include` and
+ `pg_stat_sysinfo.volumes_exclude`.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    pub metrics: Selection,
+    pub volumes: Selection,
+}
+
+impl Filter {
+    pub fn metric(&self, name: &str) -> bool {
+        self.metrics.selects(&[name])
+    }
+
+    pub fn volume(&self, mount_point: &str, file_system: &str) -> bool {
+        self.volumes.selects(&[mount_point, file_system])
+    }
+}
+
+/**
+ Glob patterns (with `*` and `?`) to include and exclude. When there are no
+ include patterns, everything not excluded is selected.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Selection {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl Selection {
+    /**
+     Parses a comma separated list of patterns, where patterns prefixed with
+     `!` are excluded: `*, !swap_*`.
+    */
+    pub fn parse(list: &str) -> Self {
+        let mut selection = Selection::default();
+        for pattern in split(list) {
+            match pattern.strip_prefix('!') {
+                Some(excluded) => selection.exclude.push(excluded.to_string()),
+                None => selection.include.push(pattern),
+            }
+        }
+        selection
+    }
+
+    pub fn from_lists(include: &str, exclude: &str) -> Self {
+        Selection {
+            include: split(include),
+            exclude: split(exclude),
+        }
+    }
+
+    /**
+     Whether any of several names for a thing -- like a mount point and a
+     filesystem type -- is selected, and none are excluded.
+    */
+    pub fn selects(&self, names: &[&str]) -> bool {
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|p| names.iter().any(|name| glob(p, name)))
+        };
+
+        (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude)
+    }
+}
+
+fn split(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn glob(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `*`, if the match fails.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, from)) => {
+                    p = star + 1;
+                    t = from + 1;
+                    backtrack = Some((star, from + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob, Selection};
+
+    #[test]
+    fn glob_matches_stars_and_question_marks() {
+        assert!(glob("cpu_usage", "cpu_usage"));
+        assert!(glob("*", ""));
+        assert!(glob("*", "anything"));
+        assert!(glob("*_usage", "cpu_usage"));
+        assert!(glob("swap_*", "swap_size"));
+        assert!(glob("swap_*", "swap_"));
+        assert!(glob("*_*", "disk_available"));
+        assert!(glob("/var/*/data", "/var/lib/pg/data"));
+        assert!(glob("cpu?", "cpu0"));
+        assert!(glob("?pu_*", "cpu_usage"));
+        assert!(glob("*n?", "banana"));
+    }
+
+    #[test]
+    fn glob_rejects_non_matches() {
+        assert!(!glob("", "cpu"));
+        assert!(!glob("cpu", "cpu_usage"));
+        assert!(!glob("*_usage", "cpu_usage_max"));
+        assert!(!glob("swap_*", "memory_swap"));
+        assert!(!glob("cpu?", "cpu"));
+        assert!(!glob("cpu?", "cpu10"));
+        assert!(!glob("/var/*/data", "/var/lib/pg/data2"));
+        assert!(!glob("*a?a", "banana!"));
+    }
+
+    #[test]
+    fn parse_splits_inclusions_and_exclusions() {
+        let selection = Selection::parse(" cpu_*, !cpu_steal ,, memory_*,!swap_* ");
+        assert_eq!(selection.include, ["cpu_*", "memory_*"]);
+        assert_eq!(selection.exclude, ["cpu_steal", "swap_*"]);
+        assert!(selection.selects(&["cpu_usage"]));
+        assert!(selection.selects(&["memory_size"]));
+        assert!(!selection.selects(&["cpu_steal"]));
+        assert!(!selection.selects(&["swap_size"]));
+        assert!(!selection.selects(&["disk_usage"]));
+    }
+
+    #[test]
+    fn empty_include_list_selects_everything_not_excluded() {
+        let everything = Selection::parse("");
+        assert_eq!(everything, Selection::default());
+        assert!(everything.selects(&["cpu_usage"]));
+
+        let selection = Selection::parse("!swap_*");
+        assert!(selection.include.is_empty());
+        assert!(selection.selects(&["cpu_usage"]));
+        assert!(!selection.selects(&["swap_usage"]));
+    }
+
+    #[test]
+    fn selects_when_any_name_matches_and_none_is_excluded() {
+        let volumes = Selection::from_lists("/, /data*", "tmpfs");
+        assert!(volumes.selects(&["/", "ext4"]));
+        assert!(volumes.selects(&["/data2", "xfs"]));
+        assert!(!volumes.selects(&["/data", "tmpfs"]));
+        assert!(!volumes.selects(&["/boot", "ext4"]));
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use crate::collector::Report;
+
+// Anything further out than a century is as good as never, and would
+// overflow an `interval`.
+const MAX_FORECAST_SECONDS: f64 = 100.0 * 365.25 * 86400.0;
+
+/**
+ A least-squares line fitted to the cached samples of one metric, for one set
+ of dimensions.
+*/
+#[derive(Clone, Debug)]
+pub struct Forecast {
+    pub dimensions: Value,
+    pub samples: usize,
+    pub at: OffsetDateTime,
+    pub value: f64,
+    /// Change in value per second.
+    pub slope: f64,
+    /// Coefficient of determination: 1.0 is a perfect fit, 0.0 is no better
+    /// than the mean.
+    pub r_squared: f64,
+    /// Seconds from the latest sample until the fitted line reaches the
+    /// exhaustion level; `None` if the trend is flat or moving away from it.
+    pub seconds_to_exhaustion: Option<f64>,
+}
+
+/**
+ The level at which a metric is exhausted: usage metrics are exhausted at
+ 100%; sizes and amounts available, at zero.
+*/
+pub fn exhaustion_level(metric: &str) -> f64 {
+    if metric.ends_with("_usage") {
+        100.0
+    } else {
+        0.0
+    }
+}
+
+pub fn forecast(reports: &[Report], metric: &str) -> Vec<Forecast> {
+    let mut series: BTreeMap<String, (Value, Vec<(OffsetDateTime, f64)>)> = BTreeMap::new();
+
+    for report in reports {
+        for (name, dimensions, at, value) in report.rows() {
+            if name != metric || !value.is_finite() {
+                continue;
+            }
+            series
+                .entry(dimensions.to_string())
+                .or_insert_with(|| (dimensions, vec![]))
+                .1
+                .push((at, value));
+        }
+    }
+
+    let level = exhaustion_level(metric);
+
+    series
+        .into_values()
+        .filter_map(|(dimensions, mut points)| {
+            points.sort_by_key(|(at, _)| *at);
+            let (at, value) = *points.last()?;
+            let (slope, r_squared) = fit(&points)?;
+            let seconds = (level - value) / slope;
+            let seconds_to_exhaustion = (seconds.is_finite()
+                && (0.0..MAX_FORECAST_SECONDS).contains(&seconds))
+            .then_some(seconds);
+
+            Some(Forecast {
+                dimensions,
+                samples: points.len(),
+                at,
+                value,
+                slope,
+                r_squared,
+                seconds_to_exhaustion,
+            })
+        })
+        .collect()
+}
+
+/**
+ Ordinary least squares over (seconds, value), returning the slope and the
+ coefficient of determination. Times are taken relative to the first sample
+ to preserve precision.
+*/
+fn fit(points: &[(OffsetDateTime, f64)]) -> Option<(f64, f64)> {
+    if points.len() < 2 {
+        return None;
+    }
+
+    let origin = points[0].0;
+    let xs: Vec<f64> = points
+        .iter()
+        .map(|(at, _)| (*at - origin).as_seconds_f64())
+        .collect();
+    let ys: Vec<f64> = points.iter().map(|(_, v)| *v).collect();
+    let n = points.len() as f64;
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = ys.iter().sum::<f64>() / n;
+
+    let (mut sxx, mut sxy, mut syy) = (0.0, 0.0, 0.0);
+    for (x, y) in xs.iter().zip(&ys) {
+        let (dx, dy) = (x - mean_x, y - mean_y);
+        sxx += dx * dx;
+        sxy += dx * dy;
+        syy += dy * dy;
+    }
+
+    if sxx == 0.0 {
+        return None;
+    }
+
+    let slope = sxy / sxx;
+    let r_squared = if syy == 0.0 {
+        1.0
+    } else {
+        (sxy * sxy) / (sxx * syy)
+    };
+
+    Some((slope, r_squared))
+}
+
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+
+    use super::{fit, forecast};
+    use crate::collector::{Report, Sample};
+
+    fn at(seconds: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(1_674_000_000 + seconds).unwrap()
+    }
+
+    fn reports(metric: &str, points: &[(i64, f64)]) -> Vec<Report> {
+        points
+            .iter()
+            .map(|&(seconds, value)| Report {
+                at: at(seconds),
+                samples: vec![Sample::new(metric, &[("fs", "/")], value)],
+            })
+            .collect()
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn fits_a_line() {
+        let points: Vec<_> = (0..10)
+            .map(|i| (at(i * 10), 10.0 + i as f64 * 5.0))
+            .collect();
+        let (slope, r_squared) = fit(&points).unwrap();
+        assert_close(slope, 0.5);
+        assert_close(r_squared, 1.0);
+    }
+
+    #[test]
+    fn r_squared_measures_scatter() {
+        let points = [(at(0), 0.0), (at(1), 2.0), (at(2), 1.0), (at(3), 3.0)];
+        let (slope, r_squared) = fit(&points).unwrap();
+        assert_close(slope, 0.8);
+        assert_close(r_squared, 0.64);
+    }
+
+    #[test]
+    fn needs_two_times_to_fit() {
+        assert!(fit(&[(at(0), 1.0)]).is_none());
+        assert!(fit(&[(at(0), 1.0), (at(0), 2.0)]).is_none());
+    }
+
+    #[test]
+    fn usage_is_exhausted_at_100() {
+        let forecasts = forecast(
+            &reports("disk_usage", &[(0, 50.0), (100, 60.0)]),
+            "disk_usage",
+        );
+        assert_eq!(forecasts.len(), 1);
+        let f = &forecasts[0];
+        assert_eq!(f.dimensions, serde_json::json!({"fs": "/"}));
+        assert_eq!(f.samples, 2);
+        assert_eq!(f.at, at(100));
+        assert_eq!(f.value, 60.0);
+        assert_close(f.seconds_to_exhaustion.unwrap(), 400.0);
+    }
+
+    #[test]
+    fn amounts_available_are_exhausted_at_zero() {
+        let points = [(0, 1000.0), (100, 900.0)];
+        let forecasts = forecast(&reports("disk_available", &points), "disk_available");
+        assert_close(forecasts[0].slope, -1.0);
+        assert_close(forecasts[0].seconds_to_exhaustion.unwrap(), 900.0);
+    }
+
+    #[test]
+    fn flat_and_receding_trends_are_never_exhausted() {
+        let flat = [(0, 50.0), (100, 50.0)];
+        let receding = [(0, 60.0), (100, 50.0)];
+        for points in [flat, receding] {
+            let forecasts = forecast(&reports("disk_usage", &points), "disk_usage");
+            assert_eq!(forecasts.len(), 1);
+            assert_eq!(forecasts[0].seconds_to_exhaustion, None);
+        }
+    }
+
+    #[test]
+    fn exhaustion_beyond_a_century_is_never() {
+        // A point a decade, with 50 points to go: five centuries.
+        let decade = 10 * 365 * 86400;
+        let forecasts = forecast(
+            &reports("disk_usage", &[(0, 49.0), (decade, 50.0)]),
+            "disk_usage",
+        );
+        assert!(forecasts[0].slope > 0.0);
+        assert_eq!(forecasts[0].seconds_to_exhaustion, None);
+
+        let forecasts = forecast(
+            &reports("disk_usage", &[(0, 99.0), (decade, 99.5)]),
+            "disk_usage",
+        );
+        assert!(forecasts[0].seconds_to_exhaustion.is_some());
+    }
+
+    #[test]
+    fn fits_each_series_of_the_metric_alone() {
+        let mut reports = reports("disk_usage", &[(0, 50.0), (100, 60.0)]);
+        reports[0]
+            .samples
+            .push(Sample::new("disk_usage", &[("fs", "/data")], 10.0));
+        reports[1]
+            .samples
+            .push(Sample::new("disk_usage", &[("fs", "/data")], f64::NAN));
+        reports[1]
+            .samples
+            .push(Sample::new("disk_available", &[("fs", "/")], 1.0));
+
+        let forecasts = forecast(&reports, "disk_usage");
+        // `/data` has only one usable sample.
+        assert_eq!(forecasts.len(), 1);
+        assert_eq!(forecasts[0].dimensions, serde_json::json!({"fs": "/"}));
+        assert_eq!(forecasts[0].samples, 2);
+    }
+}
@@ -0,0 +1,114 @@
+use std::fs;
+use std::path::Path;
+
+use sysinfo::{CpuExt, SystemExt};
+use time::OffsetDateTime;
+
+/**
+ Information about the host that does not change, or changes rarely. It
+ describes where reports came from when they are gathered from many servers.
+*/
+#[derive(Clone, Debug)]
+pub struct HostInfo {
+    pub hostname: Option<String>,
+    pub kernel_version: Option<String>,
+    pub os_name: Option<String>,
+    pub os_version: Option<String>,
+    pub architecture: String,
+    pub cpu_brand: String,
+    pub cpu_vendor: String,
+    /// In MHz.
+    pub cpu_frequency: u64,
+    pub physical_cores: Option<usize>,
+    pub logical_cores: usize,
+    pub memory_size: u64,
+    pub boot_time: OffsetDateTime,
+    pub uptime: u64,
+    pub container: bool,
+    pub virtual_machine: bool,
+}
+
+pub fn host_info() -> HostInfo {
+    let mut client = sysinfo::System::new();
+    client.refresh_cpu();
+    client.refresh_memory();
+
+    let cpu = client.global_cpu_info();
+    let cpu_frequency = client
+        .cpus()
+        .iter()
+        .map(|cpu| cpu.frequency())
+        .max()
+        .unwrap_or_default();
+    let boot_time = OffsetDateTime::from_unix_timestamp(client.boot_time() as i64)
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+
+    HostInfo {
+        hostname: client.host_name(),
+        kernel_version: client.kernel_version(),
+        os_name: client.name(),
+        os_version: client.os_version(),
+        architecture: std::env::consts::ARCH.to_string(),
+        cpu_brand: cpu.brand().trim().to_string(),
+        cpu_vendor: cpu.vendor_id().to_string(),
+        cpu_frequency,
+        physical_cores: client.physical_core_count(),
+        logical_cores: client.cpus().len(),
+        memory_size: client.total_memory(),
+        boot_time,
+        uptime: client.uptime(),
+        container: in_container(),
+        virtual_machine: in_virtual_machine(),
+    }
+}
+
+/**
+ Container runtimes leave marker files behind, and put processes in cgroups
+ named after themselves.
+*/
+fn in_container() -> bool {
+    let markers = ["/.dockerenv", "/run/.containerenv"];
+    if markers.iter().any(|p| Path::new(p).exists()) {
+        return true;
+    }
+
+    let runtimes = ["docker", "kubepods", "containerd", "lxc", "libpod"];
+    fs::read_to_string("/proc/1/cgroup")
+        .map(|text| runtimes.iter().any(|r| text.contains(r)))
+        .unwrap_or(false)
+}
+
+/**
+ Hypervisors announce themselves with a CPU flag, and usually in the DMI
+ system information as well.
+*/
+fn in_virtual_machine() -> bool {
+    let flagged = fs::read_to_string("/proc/cpuinfo")
+        .map(|text| {
+            text.lines()
+                .filter(|line| line.starts_with("flags"))
+                .any(|line| line.split_whitespace().any(|f| f == "hypervisor"))
+        })
+        .unwrap_or(false);
+    if flagged {
+        return true;
+    }
+
+    let vendors = [
+        "QEMU",
+        "KVM",
+        "VMware",
+        "VirtualBox",
+        "Xen",
+        "Microsoft Corporation",
+        "Amazon EC2",
+        "Google Compute Engine",
+    ];
+    [
+        "/sys/class/dmi/id/sys_vendor",
+        "/sys/class/dmi/id/product_name",
+    ]
+    .iter()
+    .filter_map(|p| fs::read_to_string(p).ok())
+    .any(|text| vendors.iter().any(|v| text.contains(v)))
+}
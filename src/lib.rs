@@ -7,12 +7,24 @@ use pgrx::*;
 use serde_json::Value;
 use time::OffsetDateTime;
 
+mod alerts;
 mod cache_worker;
 mod collector;
+mod compact;
+mod cpu_baseline;
 mod crate_info;
+mod filter;
+mod forecast;
+mod host;
 mod init;
+mod mounts;
+mod notify;
+mod privileges;
 mod settings;
 mod shmem_ring_buffer;
+mod sources;
+mod summary;
+mod worker_status;
 
 pgrx::pg_module_magic!();
 
@@ -27,6 +39,7 @@ fn pg_stat_sysinfo_collect() -> TableIterator<
     ),
 > {
     let mut instance = collector::singleton();
+    instance.configure(&settings::read_or_default().filter);
 
     if !instance.is_initialized() {
         notice!("Initializing system information caches.");
@@ -39,14 +52,229 @@ fn pg_stat_sysinfo_collect() -> TableIterator<
 }
 
 #[pg_extern(stable)]
-fn pg_stat_sysinfo_cache_summary(
-) -> TableIterator<'static, (name!(bytes_used, i64), name!(items, i64))> {
+fn pg_stat_sysinfo_metrics() -> TableIterator<
+    'static,
+    (
+        name!(source, String),
+        name!(metric, String),
+        name!(unit, String),
+        name!(description, String),
+    ),
+> {
+    let metrics: Vec<_> = collector::singleton()
+        .metrics()
+        .into_iter()
+        .map(|(source, m)| {
+            (
+                source.to_string(),
+                m.name.to_string(),
+                m.unit.to_string(),
+                m.description.to_string(),
+            )
+        })
+        .collect();
+
+    TableIterator::new(metrics.into_iter())
+}
+
+#[pg_extern(stable)]
+fn pg_stat_sysinfo_cache_info() -> TableIterator<
+    'static,
+    (
+        name!(bytes_used, i64),
+        name!(items, i64),
+        name!(capacity, i64),
+        name!(item_average_bytes, f32),
+        name!(page_bytes, Vec<i64>),
+        name!(page_items, Vec<i64>),
+        name!(oldest, Option<TimestampWithTimeZone>),
+        name!(newest, Option<TimestampWithTimeZone>),
+        name!(covered_seconds, Option<f64>),
+        name!(estimated_retention_seconds, Option<f64>),
+        name!(rotations, i64),
+        name!(evicted_items, i64),
+        name!(reset_at, Option<TimestampWithTimeZone>),
+        name!(write_errors, i64),
+        name!(corrupt_records, i64),
+        name!(compression_ratio, f64),
+    ),
+> {
     let info = cache_worker::cache_info();
-    let translated = (info.bytes_used as i64, info.items as i64);
+    let buffer = &info.buffer;
+    let translated = (
+        buffer.bytes_used as i64,
+        buffer.items as i64,
+        buffer.capacity as i64,
+        buffer.item_average_bytes,
+        buffer.page_bytes.iter().map(|&n| n as i64).collect(),
+        buffer.page_items.iter().map(|&n| n as i64).collect(),
+        info.oldest
+            .and_then(|t| TimestampWithTimeZone::try_from(t).ok()),
+        info.newest
+            .and_then(|t| TimestampWithTimeZone::try_from(t).ok()),
+        info.covered().map(|d| d.as_secs_f64()),
+        info.estimated_retention().map(|d| d.as_secs_f64()),
+        buffer.rotations as i64,
+        buffer.evicted as i64,
+        buffer
+            .reset_at
+            .and_then(|t| TimestampWithTimeZone::try_from(t).ok()),
+        info.write_errors as i64,
+        buffer.corrupt_records as i64,
+        buffer.compression_ratio,
+    );
 
     TableIterator::new(vec![translated].into_iter())
 }
 
+extension_sql!(
+    r#"
+    CREATE FUNCTION pg_stat_sysinfo_cache_summary(
+    ) RETURNS TABLE (
+        bytes_used bigint,
+        items bigint,
+        capacity bigint,
+        item_average_bytes real,
+        page_bytes bigint[],
+        page_items bigint[],
+        oldest timestamptz,
+        newest timestamptz,
+        covered interval,
+        estimated_retention interval,
+        rotations bigint,
+        evicted_items bigint,
+        reset_at timestamptz,
+        write_errors bigint,
+        corrupt_records bigint,
+        compression_ratio float8
+    ) LANGUAGE sql STABLE AS $$
+        SELECT bytes_used, items, capacity, item_average_bytes,
+               page_bytes, page_items, oldest, newest,
+               make_interval(secs => covered_seconds),
+               make_interval(secs => estimated_retention_seconds),
+               rotations, evicted_items, reset_at, write_errors,
+               corrupt_records, compression_ratio
+          FROM pg_stat_sysinfo_cache_info()
+    $$;
+    "#,
+    name = "create_cache_summary",
+    requires = [pg_stat_sysinfo_cache_info]
+);
+
+#[pg_extern(volatile)]
+fn pg_stat_sysinfo_start_worker() -> bool {
+    match cache_worker::start_dynamic() {
+        Ok(started) => started,
+        Err(e) => error!("{}: {}", crate_info::CRATE, e),
+    }
+}
+
+#[pg_extern(volatile)]
+fn pg_stat_sysinfo_stop_worker() -> bool {
+    match cache_worker::stop() {
+        Ok(stopped) => stopped,
+        Err(e) => error!("{}: {}", crate_info::CRATE, e),
+    }
+}
+
+extension_sql!(
+    r#"
+    REVOKE EXECUTE ON FUNCTION pg_stat_sysinfo_start_worker() FROM PUBLIC;
+    REVOKE EXECUTE ON FUNCTION pg_stat_sysinfo_stop_worker() FROM PUBLIC;
+    "#,
+    name = "restrict_worker_control",
+    requires = [pg_stat_sysinfo_start_worker, pg_stat_sysinfo_stop_worker]
+);
+
+/**
+ Clears the cache. Only superusers may call this, unless they grant others
+ permission to.
+*/
+#[pg_extern(volatile)]
+fn pg_stat_sysinfo_reset() {
+    if let Err(e) = cache_worker::reset() {
+        error!("{}: {}", crate_info::CRATE, e);
+    }
+}
+
+extension_sql!(
+    r#"
+    REVOKE EXECUTE ON FUNCTION pg_stat_sysinfo_reset() FROM PUBLIC;
+    "#,
+    name = "restrict_reset",
+    requires = [pg_stat_sysinfo_reset]
+);
+
+#[pg_extern(volatile)]
+fn pg_stat_sysinfo_worker_state() -> TableIterator<
+    'static,
+    (
+        name!(pid, Option<i32>),
+        name!(enabled, bool),
+        name!(interval_seconds, Option<f64>),
+        name!(last_collection, Option<TimestampWithTimeZone>),
+        name!(last_duration_seconds, Option<f64>),
+        name!(collections, i64),
+        name!(late_ticks, i64),
+        name!(missed_ticks, i64),
+        name!(write_errors, i64),
+        name!(last_error, Option<String>),
+        name!(last_error_at, Option<TimestampWithTimeZone>),
+        name!(next_run, Option<TimestampWithTimeZone>),
+    ),
+> {
+    let tstz =
+        |at: Option<OffsetDateTime>| at.and_then(|at| TimestampWithTimeZone::try_from(at).ok());
+    let status = worker_status::read().unwrap_or_default();
+    let last_error = (!status.last_error.is_empty()).then(|| status.last_error.to_string());
+    let row = (
+        cache_worker::worker_pid(),
+        status.enabled,
+        status.interval.map(|d| d.as_secs_f64()),
+        tstz(status.last_collection),
+        status.last_duration.map(|d| d.as_secs_f64()),
+        status.collections as i64,
+        status.late_ticks as i64,
+        status.missed_ticks as i64,
+        status.write_errors as i64,
+        last_error,
+        tstz(status.last_error_at),
+        tstz(status.next_run),
+    );
+
+    TableIterator::new(vec![row].into_iter())
+}
+
+extension_sql!(
+    r#"
+    CREATE FUNCTION pg_stat_sysinfo_worker_status(
+    ) RETURNS TABLE (
+        pid integer,
+        enabled boolean,
+        "interval" interval,
+        last_collection timestamptz,
+        last_duration interval,
+        collections bigint,
+        late_ticks bigint,
+        missed_ticks bigint,
+        write_errors bigint,
+        last_error text,
+        last_error_at timestamptz,
+        next_run timestamptz
+    ) LANGUAGE sql VOLATILE AS $$
+        SELECT pid, enabled,
+               make_interval(secs => interval_seconds),
+               last_collection,
+               make_interval(secs => last_duration_seconds),
+               collections, late_ticks, missed_ticks,
+               write_errors, last_error, last_error_at, next_run
+          FROM pg_stat_sysinfo_worker_state()
+    $$;
+    "#,
+    name = "create_worker_status",
+    requires = [pg_stat_sysinfo_worker_state]
+);
+
 #[pg_extern(stable)]
 fn pg_stat_sysinfo_cached() -> TableIterator<
     'static,
@@ -65,6 +293,264 @@ fn pg_stat_sysinfo_cached() -> TableIterator<
     maprows(v.into_iter())
 }
 
+#[pg_extern(stable)]
+fn pg_stat_sysinfo_alerts() -> TableIterator<
+    'static,
+    (
+        name!(rule, String),
+        name!(metric, String),
+        name!(dimensions, JsonB),
+        name!(state, String),
+        name!(severity, String),
+        name!(value, f64),
+        name!(threshold, f64),
+        name!(since, Option<TimestampWithTimeZone>),
+        name!(at, Option<TimestampWithTimeZone>),
+    ),
+> {
+    let masking = privileges::masking();
+    let rows: Vec<_> = alerts::active()
+        .into_iter()
+        .map(|alert| {
+            let dimensions = serde_json::from_str(&alert.dimensions).unwrap_or(Value::Null);
+            (
+                alert.rule,
+                alert.metric,
+                JsonB(masking.dimensions(dimensions)),
+                alert.state.as_str().to_string(),
+                alert.severity.as_str().to_string(),
+                alert.value,
+                alert.threshold,
+                TimestampWithTimeZone::try_from(alert.since).ok(),
+                TimestampWithTimeZone::try_from(alert.at).ok(),
+            )
+        })
+        .collect();
+
+    TableIterator::new(rows.into_iter())
+}
+
+#[pg_extern(stable)]
+fn pg_stat_sysinfo_fit(
+    metric: &str,
+) -> TableIterator<
+    'static,
+    (
+        name!(dimensions, JsonB),
+        name!(samples, i64),
+        name!(at, TimestampWithTimeZone),
+        name!(value, f64),
+        name!(slope, f64),
+        name!(r_squared, f64),
+        name!(seconds_to_exhaustion, Option<f64>),
+    ),
+> {
+    let masking = privileges::masking();
+    let reports = cache_worker::reports();
+    let rows: Vec<_> = forecast::forecast(&reports, metric)
+        .into_iter()
+        .filter_map(|f| {
+            let at = TimestampWithTimeZone::try_from(f.at).ok()?;
+            Some((
+                JsonB(masking.dimensions(f.dimensions)),
+                f.samples as i64,
+                at,
+                f.value,
+                f.slope,
+                f.r_squared,
+                f.seconds_to_exhaustion,
+            ))
+        })
+        .collect();
+
+    TableIterator::new(rows.into_iter())
+}
+
+extension_sql!(
+    r#"
+    CREATE FUNCTION pg_stat_sysinfo_forecast(
+        metric text DEFAULT 'disk_available',
+        horizon interval DEFAULT '24 hours'
+    ) RETURNS TABLE (
+        dimensions jsonb,
+        samples bigint,
+        at timestamptz,
+        value float8,
+        rate_per_hour float8,
+        r_squared float8,
+        exhausted_at timestamptz,
+        time_to_exhaustion interval,
+        within_horizon boolean
+    ) LANGUAGE sql STABLE AS $$
+        SELECT f.dimensions, f.samples, f.at, f.value,
+               f.slope * 3600, f.r_squared,
+               f.at + make_interval(secs => f.seconds_to_exhaustion),
+               make_interval(secs => f.seconds_to_exhaustion),
+               coalesce(make_interval(secs => f.seconds_to_exhaustion)
+                        <= horizon, false)
+          FROM pg_stat_sysinfo_fit(metric) AS f
+    $$;
+    "#,
+    name = "create_forecast",
+    requires = [pg_stat_sysinfo_fit]
+);
+
+#[pg_extern(stable)]
+fn pg_stat_sysinfo_summarize(
+    window_seconds: f64,
+) -> TableIterator<
+    'static,
+    (
+        name!(metric, String),
+        name!(dimensions, JsonB),
+        name!(samples, i64),
+        name!(min, f64),
+        name!(max, f64),
+        name!(avg, f64),
+        name!(stddev, Option<f64>),
+        name!(p50, f64),
+        name!(p95, f64),
+        name!(p99, f64),
+        name!(last, f64),
+        name!(last_at, Option<TimestampWithTimeZone>),
+    ),
+> {
+    // Windows longer than the cache simply include everything.
+    let since = if (0.0..1e10).contains(&window_seconds) {
+        let window = time::Duration::seconds_f64(window_seconds);
+        OffsetDateTime::now_utc() - window
+    } else if window_seconds < 0.0 {
+        OffsetDateTime::now_utc()
+    } else {
+        OffsetDateTime::UNIX_EPOCH
+    };
+    let masking = privileges::masking();
+    let reports = cache_worker::reports();
+    let rows: Vec<_> = summary::summarize(&reports, since)
+        .into_iter()
+        .map(|s| {
+            (
+                s.metric,
+                JsonB(masking.dimensions(s.dimensions)),
+                s.samples as i64,
+                s.min,
+                s.max,
+                s.avg,
+                s.stddev,
+                s.p50,
+                s.p95,
+                s.p99,
+                s.last,
+                TimestampWithTimeZone::try_from(s.last_at).ok(),
+            )
+        })
+        .collect();
+
+    TableIterator::new(rows.into_iter())
+}
+
+extension_sql!(
+    r#"
+    CREATE FUNCTION pg_stat_sysinfo_summary(
+        "window" interval DEFAULT '1 hour'
+    ) RETURNS TABLE (
+        metric text,
+        dimensions jsonb,
+        samples bigint,
+        min float8,
+        max float8,
+        avg float8,
+        stddev float8,
+        p50 float8,
+        p95 float8,
+        p99 float8,
+        last float8,
+        last_at timestamptz
+    ) LANGUAGE sql STABLE AS $$
+        SELECT *
+          FROM pg_stat_sysinfo_summarize(
+                 extract(epoch FROM "window")::float8)
+    $$;
+    "#,
+    name = "create_summary",
+    requires = [pg_stat_sysinfo_summarize]
+);
+
+#[pg_extern(stable)]
+fn pg_stat_sysinfo_host_info() -> TableIterator<
+    'static,
+    (
+        name!(hostname, Option<String>),
+        name!(kernel_version, Option<String>),
+        name!(os_name, Option<String>),
+        name!(os_version, Option<String>),
+        name!(architecture, String),
+        name!(cpu_brand, String),
+        name!(cpu_vendor, String),
+        name!(cpu_frequency_mhz, i64),
+        name!(physical_cores, Option<i32>),
+        name!(logical_cores, i32),
+        name!(memory_size, f64),
+        name!(boot_time, Option<TimestampWithTimeZone>),
+        name!(uptime_seconds, i64),
+        name!(container, bool),
+        name!(virtual_machine, bool),
+    ),
+> {
+    let h = host::host_info();
+    let row = (
+        h.hostname,
+        h.kernel_version,
+        h.os_name,
+        h.os_version,
+        h.architecture,
+        h.cpu_brand,
+        h.cpu_vendor,
+        h.cpu_frequency as i64,
+        h.physical_cores.map(|n| n as i32),
+        h.logical_cores as i32,
+        h.memory_size as f64,
+        TimestampWithTimeZone::try_from(h.boot_time).ok(),
+        h.uptime as i64,
+        h.container,
+        h.virtual_machine,
+    );
+
+    TableIterator::new(vec![row].into_iter())
+}
+
+extension_sql!(
+    r#"
+    CREATE FUNCTION pg_stat_sysinfo_host(
+    ) RETURNS TABLE (
+        hostname text,
+        kernel_version text,
+        os_name text,
+        os_version text,
+        architecture text,
+        cpu_brand text,
+        cpu_vendor text,
+        cpu_frequency_mhz bigint,
+        physical_cores integer,
+        logical_cores integer,
+        memory_size float8,
+        boot_time timestamptz,
+        uptime interval,
+        container boolean,
+        virtual_machine boolean
+    ) LANGUAGE sql STABLE AS $$
+        SELECT hostname, kernel_version, os_name, os_version, architecture,
+               cpu_brand, cpu_vendor, cpu_frequency_mhz,
+               physical_cores, logical_cores, memory_size, boot_time,
+               make_interval(secs => uptime_seconds),
+               container, virtual_machine
+          FROM pg_stat_sysinfo_host_info()
+    $$;
+    "#,
+    name = "create_host",
+    requires = [pg_stat_sysinfo_host_info]
+);
+
 extension_sql!(
     r#"
     CREATE VIEW pg_stat_sysinfo AS
@@ -86,9 +572,11 @@ fn maprows<'a, I: Iterator<Item = (String, Value, OffsetDateTime, f64)> + 'a>(
         name!(value, f64),
     ),
 > {
-    let translated = iter.filter_map(|(metric, dimensions, at, value)| {
+    let masking = privileges::masking();
+    let translated = iter.filter_map(move |(metric, dimensions, at, value)| {
+        let dimensions = JsonB(masking.dimensions(dimensions));
         match TimestampWithTimeZone::try_from(at) {
-            Ok(tstz) => Some((metric, JsonB(dimensions), tstz, value)),
+            Ok(tstz) => Some((metric, dimensions, tstz, value)),
             Err(_err) => {
                 warning!("Failed to translate timestamp: {:?}", at);
                 None
@@ -98,3 +586,48 @@ fn maprows<'a, I: Iterator<Item = (String, Value, OffsetDateTime, f64)> + 'a>(
 
     TableIterator::new(translated)
 }
+
+extension_sql!(
+    r#"
+    -- Host details are for monitoring roles, not everyone who can connect.
+    REVOKE EXECUTE ON FUNCTION
+        pg_stat_sysinfo_collect(),
+        pg_stat_sysinfo_metrics(),
+        pg_stat_sysinfo_cache_info(),
+        pg_stat_sysinfo_cache_summary(),
+        pg_stat_sysinfo_start_worker(),
+        pg_stat_sysinfo_stop_worker(),
+        pg_stat_sysinfo_worker_state(),
+        pg_stat_sysinfo_worker_status(),
+        pg_stat_sysinfo_cached(),
+        pg_stat_sysinfo_alerts(),
+        pg_stat_sysinfo_fit(text),
+        pg_stat_sysinfo_forecast(text, interval),
+        pg_stat_sysinfo_summarize(float8),
+        pg_stat_sysinfo_summary(interval),
+        pg_stat_sysinfo_host_info(),
+        pg_stat_sysinfo_host()
+      FROM PUBLIC;
+
+    GRANT EXECUTE ON FUNCTION
+        pg_stat_sysinfo_collect(),
+        pg_stat_sysinfo_metrics(),
+        pg_stat_sysinfo_cache_info(),
+        pg_stat_sysinfo_cache_summary(),
+        pg_stat_sysinfo_worker_state(),
+        pg_stat_sysinfo_worker_status(),
+        pg_stat_sysinfo_cached(),
+        pg_stat_sysinfo_alerts(),
+        pg_stat_sysinfo_fit(text),
+        pg_stat_sysinfo_forecast(text, interval),
+        pg_stat_sysinfo_summarize(float8),
+        pg_stat_sysinfo_summary(interval),
+        pg_stat_sysinfo_host_info(),
+        pg_stat_sysinfo_host()
+      TO pg_read_all_stats;
+
+    GRANT SELECT ON pg_stat_sysinfo TO pg_read_all_stats;
+    "#,
+    name = "grant_privileges",
+    finalize
+);
@@ -0,0 +1,37 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/**
+ Detects mounts and unmounts by checking whether the mount table has changed
+ since it was last looked at. Reading the table is cheap enough to do on
+ every tick of the cache worker.
+*/
+#[derive(Debug, Default)]
+pub struct MountWatcher {
+    last: Option<u64>,
+}
+
+impl MountWatcher {
+    /**
+     Whether the mount table changed since the last call. The first call
+     only takes note of the table. Where there is no mount table to read,
+     nothing ever changes.
+    */
+    pub fn changed(&mut self) -> bool {
+        let digest = match fs::read(MOUNTINFO) {
+            Ok(bytes) => {
+                let mut hasher = DefaultHasher::new();
+                bytes.hash(&mut hasher);
+                hasher.finish()
+            }
+            Err(_) => return false,
+        };
+
+        let changed = self.last.map(|d| d != digest).unwrap_or(false);
+        self.last = Some(digest);
+        changed
+    }
+}
@@ -0,0 +1,104 @@
+use std::ffi::{c_char, CString};
+
+use pgrx::bgworkers::BackgroundWorker;
+use pgrx::*;
+use serde_json::json;
+use time::format_description::well_known::Rfc3339;
+
+use crate::collector::Report;
+use crate::crate_info::CRATE;
+use crate::privileges;
+
+// Postgres rejects payloads of `NOTIFY_PAYLOAD_MAX_LENGTH` (8000) bytes or
+// more, counting the terminating NUL.
+const PAYLOAD_LIMIT: usize = 8000 - 1;
+// Room for the closing of the rows array and the truncation count.
+const PAYLOAD_TRAILER: usize = 32;
+// Channel names are identifiers, so they are limited to `NAMEDATALEN - 1`.
+const CHANNEL_LIMIT: usize = 63;
+
+// `commands/async.h` is not among the headers pgrx generates bindings for.
+#[pg_guard]
+extern "C" {
+    fn Async_Notify(channel: *const c_char, payload: *const c_char);
+    #[cfg(any(feature = "pg11", feature = "pg12", feature = "pg13", feature = "pg14"))]
+    fn ProcessCompletedNotifies();
+}
+
+/**
+ Renders a report as compact JSON, suitable for a `NOTIFY` payload:
+
+   {"at":"2023-01-17T20:40:24.74495Z",
+    "rows":[["load_average",{"duration":"1m"},4.34],...]}
+
+ Rows are added in report order until the payload limit is reached; the
+ number of rows left out is recorded under `"truncated"`. Since volumes come
+ last in a report, hosts with many mounts lose only some disk rows. Mount
+ points are masked, since any user may listen.
+*/
+pub fn payload(report: &Report) -> String {
+    let at = report.at.format(&Rfc3339).unwrap_or_default();
+    let mut text = format!(r#"{{"at":{},"rows":["#, json!(at));
+    let budget = PAYLOAD_LIMIT - PAYLOAD_TRAILER;
+    let mut truncated = 0;
+    let mut first = true;
+    let masking = privileges::public();
+
+    for (metric, dimensions, _, value) in report.rows() {
+        let dimensions = masking.dimensions(dimensions);
+        let row = json!([metric, dimensions, value]).to_string();
+        let needed = row.len() + if first { 0 } else { 1 };
+        if truncated > 0 || text.len() + needed > budget {
+            truncated += 1;
+            continue;
+        }
+        if !first {
+            text.push(',');
+        }
+        text.push_str(&row);
+        first = false;
+    }
+
+    text.push(']');
+    if truncated > 0 {
+        text.push_str(&format!(r#","truncated":{truncated}"#));
+    }
+    text.push('}');
+
+    text
+}
+
+/**
+ Sends a notification from the background worker. The notification is
+ queued when the transaction commits, so this has to be called outside of
+ any transaction the worker might have open. Before Postgres 15, listeners
+ are only signalled once the worker processes its completed notifies, which
+ a backend does as it goes idle but a background worker must do itself.
+*/
+pub fn send(channel: &str, payload: &str) {
+    if channel.len() > CHANNEL_LIMIT {
+        warning!(
+            "{}: Not notifying: channel name is longer than {} bytes: {}",
+            CRATE,
+            CHANNEL_LIMIT,
+            channel
+        );
+        return;
+    }
+
+    let (channel, payload) = match (CString::new(channel), CString::new(payload)) {
+        (Ok(channel), Ok(payload)) => (channel, payload),
+        _ => {
+            warning!("{}: Not notifying: unexpected NUL byte.", CRATE);
+            return;
+        }
+    };
+
+    BackgroundWorker::transaction(|| unsafe {
+        Async_Notify(channel.as_ptr(), payload.as_ptr());
+    });
+    #[cfg(any(feature = "pg11", feature = "pg12", feature = "pg13", feature = "pg14"))]
+    unsafe {
+        ProcessCompletedNotifies();
+    }
+}
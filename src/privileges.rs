@@ -0,0 +1,49 @@
+use pgrx::*;
+use serde_json::Value;
+
+/// Dimensions that reveal details of the host -- like the layout of its
+/// file systems -- to be hidden from callers who may not read all
+/// statistics.
+const SENSITIVE_DIMENSIONS: &[&str] = &["fs"];
+
+const MASKED: &str = "<insufficient privilege>";
+
+/**
+ Masks sensitive details for callers without the privileges of
+ `pg_read_all_stats` (which includes `pg_monitor` and superusers), the way
+ `pg_stat_activity` masks the queries of other users.
+*/
+pub struct Masking {
+    privileged: bool,
+}
+
+pub fn masking() -> Masking {
+    let query = "SELECT pg_catalog.pg_has_role('pg_read_all_stats', 'USAGE')";
+    let privileged = Spi::get_one::<bool>(query).ok().flatten().unwrap_or(false);
+
+    Masking { privileged }
+}
+
+/**
+ Masking for output that anyone may see, like notification payloads: any
+ user who can connect to the database can `LISTEN`.
+*/
+pub fn public() -> Masking {
+    Masking { privileged: false }
+}
+
+impl Masking {
+    pub fn dimensions(&self, mut dimensions: Value) -> Value {
+        if self.privileged {
+            return dimensions;
+        }
+        if let Value::Object(map) = &mut dimensions {
+            for key in SENSITIVE_DIMENSIONS {
+                if let Some(value) = map.get_mut(*key) {
+                    *value = Value::String(MASKED.to_string());
+                }
+            }
+        }
+        dimensions
+    }
+}
@@ -2,17 +2,55 @@ use std::ffi::{c_char, CStr};
 use std::ptr;
 
 use anyhow::anyhow;
+use lazy_static::lazy_static;
 use pgrx::*;
 use std::time::Duration;
 
 use crate::crate_info::CRATE;
+use crate::filter::{Filter, Selection};
+use crate::sources;
 
 #[derive(Debug, Default)]
 pub struct Settings {
     pub interval: Option<Duration>,
+    /// Intervals for metric sources that are collected more or less often
+    /// than `interval`.
+    pub group_intervals: Vec<(&'static str, Duration)>,
+    pub notify_channel: Option<String>,
+    pub filter: Filter,
+    pub disk_discovery_interval: Option<Duration>,
+    pub watch_mounts: bool,
 }
 
 pub static INTERVAL: GucSetting<Option<&'static str>> = GucSetting::new(None);
+pub static NOTIFY_CHANNEL: GucSetting<Option<&'static str>> = GucSetting::new(None);
+pub static METRICS: GucSetting<Option<&'static str>> = GucSetting::new(None);
+pub static VOLUMES_INCLUDE: GucSetting<Option<&'static str>> = GucSetting::new(None);
+pub static VOLUMES_EXCLUDE: GucSetting<Option<&'static str>> = GucSetting::new(None);
+pub static DISK_DISCOVERY_INTERVAL: GucSetting<Option<&'static str>> =
+    GucSetting::new(Some("1min"));
+pub static WATCH_MOUNTS: GucSetting<bool> = GucSetting::new(true);
+pub static SHARED_CPU_BASELINE: GucSetting<bool> = GucSetting::new(true);
+pub static SYSFS_ROOT: GucSetting<Option<&'static str>> = GucSetting::new(Some("/sys"));
+pub static DATABASE: GucSetting<Option<&'static str>> = GucSetting::new(None);
+
+lazy_static! {
+    /**
+     One `{CRATE}.interval.<source>` setting for every metric source. The
+     settings must live forever, since Postgres holds on to them.
+    */
+    static ref GROUP_INTERVALS: Vec<(&'static str, &'static StrSetting)> =
+        sources::names()
+            .into_iter()
+            .map(|name| {
+                let setting: &'static StrSetting =
+                    Box::leak(Box::new(GucSetting::new(None)));
+                (name, setting)
+            })
+            .collect();
+}
+
+type StrSetting = GucSetting<Option<&'static str>>;
 
 pub fn define() {
     GucRegistry::define_string_guc(
@@ -23,12 +61,163 @@ pub fn define() {
         GucContext::Sighup,
         GucFlags::UNIT_S,
     );
+    for (group, setting) in GROUP_INTERVALS.iter() {
+        GucRegistry::define_string_guc(
+            &format!("{CRATE}.interval.{group}"),
+            "The interval at which to collect metrics from one source.",
+            "Overrides the interval for this source's metrics, so that \
+             slowly changing metrics can be collected less often.",
+            *setting,
+            GucContext::Sighup,
+            GucFlags::UNIT_S,
+        );
+    }
+    GucRegistry::define_string_guc(
+        &format!("{CRATE}.notify_channel"),
+        "Channel on which to NOTIFY after each collection.",
+        "When set, the cache worker sends a compact JSON summary of each \
+         report to this channel.",
+        &NOTIFY_CHANNEL,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        &format!("{CRATE}.metrics"),
+        "Metrics to collect.",
+        "A comma separated list of glob patterns over metric names. Patterns \
+         prefixed with ! are excluded. By default, all metrics are collected.",
+        &METRICS,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        &format!("{CRATE}.volumes_include"),
+        "Volumes to collect disk metrics for.",
+        "A comma separated list of glob patterns over mount points and \
+         filesystem types. By default, all volumes are included.",
+        &VOLUMES_INCLUDE,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        &format!("{CRATE}.volumes_exclude"),
+        "Volumes not to collect disk metrics for.",
+        "A comma separated list of glob patterns over mount points and \
+         filesystem types. Exclusions take precedence over inclusions.",
+        &VOLUMES_EXCLUDE,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        &format!("{CRATE}.disk_discovery_interval"),
+        "The interval at which to look for new and removed volumes.",
+        "The cache worker reloads the list of volumes every interval.",
+        &DISK_DISCOVERY_INTERVAL,
+        GucContext::Sighup,
+        GucFlags::UNIT_S,
+    );
+    GucRegistry::define_bool_guc(
+        &format!("{CRATE}.watch_mounts"),
+        "Whether to look for new and removed volumes when mounts change.",
+        "The cache worker checks the mount table on every collection, and \
+         reloads the list of volumes when it has changed.",
+        &WATCH_MOUNTS,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        &format!("{CRATE}.shared_cpu_baseline"),
+        "Whether to measure CPU usage against a snapshot in shared memory.",
+        "Queries measure CPU usage since the last snapshot taken by the cache \
+         worker, or another query, instead of waiting to take two \
+         measurements of their own.",
+        &SHARED_CPU_BASELINE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        &format!("{CRATE}.sysfs_root"),
+        "Where sysfs is mounted.",
+        "Hardware sensors are read from class/hwmon under this directory.",
+        &SYSFS_ROOT,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        &format!("{CRATE}.database"),
+        "The database the cache worker connects to.",
+        "Alert rules are read from this database, and notifications are \
+         delivered to listeners in it. When unset, the worker connects to \
+         no database, and sends neither alerts nor notifications.",
+        &DATABASE,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
 }
 
 pub fn read() -> anyhow::Result<Settings> {
+    let interval = parse_interval(&format!("{CRATE}.interval"), &INTERVAL)?;
+
+    let mut group_intervals = vec![];
+    for (group, setting) in GROUP_INTERVALS.iter() {
+        let name = format!("{CRATE}.interval.{group}");
+        if let Some(interval) = parse_interval(&name, setting)? {
+            group_intervals.push((*group, interval));
+        }
+    }
+
+    let notify_channel = NOTIFY_CHANNEL.get().filter(|s| !s.is_empty());
+
+    let filter = Filter {
+        metrics: Selection::parse(&METRICS.get().unwrap_or_default()),
+        volumes: Selection::from_lists(
+            &VOLUMES_INCLUDE.get().unwrap_or_default(),
+            &VOLUMES_EXCLUDE.get().unwrap_or_default(),
+        ),
+    };
+
+    let name = format!("{CRATE}.disk_discovery_interval");
+    let disk_discovery_interval = parse_interval(&name, &DISK_DISCOVERY_INTERVAL)?;
+    let watch_mounts = WATCH_MOUNTS.get();
+
+    Ok(Settings {
+        interval,
+        group_intervals,
+        notify_channel,
+        filter,
+        disk_discovery_interval,
+        watch_mounts,
+    })
+}
+
+pub fn read_or_default() -> Settings {
+    match read() {
+        Ok(settings) => settings,
+        Err(e) => {
+            warning!("{}: Failed to parse settings: {:?}", CRATE, e);
+            Settings::default()
+        }
+    }
+}
+
+pub fn shared_cpu_baseline() -> bool {
+    SHARED_CPU_BASELINE.get()
+}
+
+pub fn sysfs_root() -> String {
+    SYSFS_ROOT
+        .get()
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "/sys".to_string())
+}
+
+pub fn database() -> Option<String> {
+    DATABASE.get().filter(|s| !s.is_empty())
+}
+
+fn parse_interval(name: &str, setting: &StrSetting) -> anyhow::Result<Option<Duration>> {
     let seconds = unsafe {
-        let name = format!("{CRATE}.interval");
-        let input: *const c_char = INTERVAL.get_char_ptr();
+        let input: *const c_char = setting.get_char_ptr();
 
         if input.is_null() {
             Ok(None)
@@ -39,27 +228,17 @@ pub fn read() -> anyhow::Result<Settings> {
             let flags = pg_sys::GUC_UNIT_S as i32;
             if pg_sys::parse_real(input, &mut seconds, flags, &mut hintmsg) {
                 Ok(Some(seconds))
+            } else if hintmsg.is_null() {
+                Err(anyhow!("Error parsing {}", name))
             } else {
                 let hint = CStr::from_ptr(hintmsg);
                 let s = hint.to_string_lossy();
-                Err(anyhow!("Error parsing {}: {}", &name, &s))
+                Err(anyhow!("Error parsing {}: {}", name, &s))
             }
         }
     }?;
 
-    let interval = seconds.map(from_float_seconds);
-
-    Ok(Settings { interval })
-}
-
-pub fn read_or_default() -> Settings {
-    match read() {
-        Ok(settings) => settings,
-        Err(e) => {
-            warning!("{}: Failed to parse settings: {:?}", CRATE, e);
-            Settings::default()
-        }
-    }
+    Ok(seconds.map(from_float_seconds))
 }
 
 fn from_float_seconds(seconds: f64) -> Duration {
@@ -1,11 +1,11 @@
 use std::default::Default;
-use std::io::BufReader;
 use std::marker::PhantomData;
 
 use anyhow::anyhow;
 use pgrx::*;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use time::OffsetDateTime;
 
 use crate::crate_info::CRATE;
 
@@ -14,6 +14,62 @@ const PAGE_SIZE: usize = 256 * ONE_KB;
 // const PAGE_SIZE: usize = 1 * ONE_KB;
 const PAGES: usize = 5;
 
+// Each record is framed with a header -- the format, the schema version of
+// the payload (see `Versioned`), the length of the payload and a CRC-32 of
+// the schema version and payload -- so that the end of the data in a page can
+// be told apart from a corrupted or undecodable record, and bad records can
+// be skipped.
+//
+//   format: u8 | schema: u16 (LE) | length: u32 (LE) | checksum: u32 (LE) | payload
+//
+const HEADER_SIZE: usize = 11;
+// The payload is one `T`, serialized with `serde_bare` in the layout of the
+// schema version.
+const FORMAT_ITEM: u8 = 1;
+// The payload is many `T`, in the compact encoding provided by
+// `Versioned::encode_block`.
+const FORMAT_BLOCK: u8 = 2;
+
+/**
+  Types stored in the ring buffer. `serde_bare` is positional: adding,
+  removing or reordering a field changes the layout, and bytes written with
+  one layout are misread -- or unreadable -- with another. Every record is
+  written with the schema version of its layout, so that records written by
+  an older release can be decoded and upgraded.
+*/
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// The version of the current layout. Bump it when the layout changes,
+    /// and teach `decode` to read the previous one.
+    const SCHEMA: u16;
+
+    /**
+      Decodes a payload written with the given schema version. The default
+      only understands the current layout.
+    */
+    fn decode(schema: u16, payload: &[u8]) -> anyhow::Result<Self> {
+        match schema {
+            v if v == Self::SCHEMA => Ok(serde_bare::from_slice(payload)?),
+            v => Err(anyhow!("Unknown schema version {}.", v)),
+        }
+    }
+
+    /**
+      Encodes many items at once, more compactly than one at a time. When a
+      page fills up, its records are packed into a single block, to make
+      room for more before rotating. The default does not support this.
+    */
+    fn encode_block(_items: &[Self]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn decode_block(schema: u16, _payload: &[u8]) -> anyhow::Result<Vec<Self>> {
+        Err(anyhow!(
+            "Blocks of schema version {} are not supported.",
+            schema
+        ))
+    }
+}
+
 /**
   The ring buffer is statically allocated, broken into pages of a fixed size.
   Objects are serialized into to the buffer. When the last page is full,
@@ -23,28 +79,30 @@ const PAGES: usize = 5;
 pub type ShmemRingBuffer<T> = ShmemBackedSerdeRingBuffer<PAGE_SIZE, PAGES, T>;
 
 #[derive(Clone, Debug)]
-pub struct ShmemBackedSerdeRingBuffer<
-    const N: usize,
-    const M: usize,
-    T: Serialize + DeserializeOwned,
-> {
+pub struct ShmemBackedSerdeRingBuffer<const N: usize, const M: usize, T: Versioned> {
     data: heapless::Vec<heapless::Vec<u8, N>, M>,
     counts: heapless::Vec<usize, M>,
+    // The bytes each page's records took before being packed into blocks.
+    raw_bytes: heapless::Vec<usize, M>,
+    rotations: u64,
+    // Items cleared from pages, to make room for new ones.
+    evicted: u64,
+    // Corrupt records left out when pages were packed into blocks.
+    dropped_corrupt: usize,
+    reset_at: Option<OffsetDateTime>,
     _phantom: PhantomData<T>,
 }
 
-impl<const N: usize, const M: usize, T: Serialize + DeserializeOwned>
-    ShmemBackedSerdeRingBuffer<N, M, T>
-{
+impl<const N: usize, const M: usize, T: Versioned> ShmemBackedSerdeRingBuffer<N, M, T> {
     /**
-      Writes objects as JSON to the ring buffer.
+      Writes objects to the ring buffer, as framed records.
     */
-    pub fn write(&mut self, item: T) -> anyhow::Result<()> {
+    pub fn write(&mut self, item: &T) -> anyhow::Result<()> {
         let last = self.data.last().expect("No cache pages?");
         let (used, capacity) = (last.len(), last.capacity());
 
-        let mut encoded = Vec::new();
-        serde_bare::to_writer(&mut encoded, &item)?;
+        let payload = serde_bare::to_vec(item)?;
+        let encoded = frame(FORMAT_ITEM, T::SCHEMA, &payload);
 
         if capacity / 2 < encoded.len() {
             // Although it could fit, objects should never be this big;
@@ -57,14 +115,18 @@ impl<const N: usize, const M: usize, T: Serialize + DeserializeOwned>
             ));
         }
 
-        if (used + encoded.len() + 2) >= capacity {
+        if (used + encoded.len() + 2) >= capacity && !self.compact(encoded.len()) {
             self.data.rotate_left(1);
             self.counts.rotate_left(1);
+            self.raw_bytes.rotate_left(1);
+            self.rotations += 1;
 
             if !self.data.last().expect("No cache pages?").is_empty() {
                 let was = self.stats();
+                self.evicted += *self.counts.last().expect("No counts?") as u64;
                 self.data.last_mut().expect("No cache pages?").clear();
                 *self.counts.last_mut().expect("No counts?") = 0;
+                *self.raw_bytes.last_mut().expect("No counts?") = 0;
                 let is = self.stats();
                 debug1!(
                     "{}: Rotated buffer -- cleared one page. \
@@ -84,62 +146,159 @@ impl<const N: usize, const M: usize, T: Serialize + DeserializeOwned>
         let real_last = self.data.last_mut().expect("No cache pages?");
         real_last.extend_from_slice(&encoded).map_err(err)?;
         *self.counts.last_mut().expect("No counts?") += 1;
+        *self.raw_bytes.last_mut().expect("No counts?") += encoded.len();
 
         Ok(())
     }
 
+    /**
+      Packs the records in the last page into a single block, if that leaves
+      room for the next record -- and for a good many more, so that the page
+      is not packed again on every write. Returns whether it did so. Corrupt
+      records are left out of the block, but still counted as corrupt.
+    */
+    fn compact(&mut self, next_record: usize) -> bool {
+        let last = self.data.last().expect("No cache pages?");
+        let mut items: Vec<T> = vec![];
+        let mut corrupt = 0;
+        for record in records(last) {
+            match record.and_then(decode) {
+                Some(decoded) => items.extend(decoded),
+                None => corrupt += 1,
+            }
+        }
+        if items.is_empty() {
+            return false;
+        }
+
+        let block = match T::encode_block(&items) {
+            Some(block) => block,
+            None => return false,
+        };
+        let encoded = frame(FORMAT_BLOCK, T::SCHEMA, &block);
+
+        if (encoded.len() + next_record + 2) >= last.capacity() * 3 / 4 {
+            return false;
+        }
+
+        let was = last.len();
+        let page = self.data.last_mut().expect("No cache pages?");
+        page.clear();
+        // Can not fail: it is smaller than the page.
+        let _ = page.extend_from_slice(&encoded);
+        *self.counts.last_mut().expect("No counts?") = items.len();
+        self.dropped_corrupt += corrupt;
+        debug1!(
+            "{}: Compacted page: {} -> {} ({} items).",
+            CRATE,
+            bytesize::to_string(was as u64, true),
+            bytesize::to_string(encoded.len() as u64, true),
+            items.len(),
+        );
+
+        true
+    }
+
+    /**
+      Clears all the pages, and the counts kept about them.
+    */
+    pub fn reset(&mut self) {
+        for page in self.data.iter_mut() {
+            page.clear();
+        }
+        self.counts.iter_mut().for_each(|n| *n = 0);
+        self.raw_bytes.iter_mut().for_each(|n| *n = 0);
+        self.rotations = 0;
+        self.evicted = 0;
+        self.dropped_corrupt = 0;
+        self.reset_at = Some(OffsetDateTime::now_utc());
+    }
+
+    /**
+      Appends an item already serialized in the layout of the given schema
+      version -- as an older release would have written it.
+    */
+    #[cfg(test)]
+    pub fn write_versioned(&mut self, schema: u16, item: &[u8]) {
+        let encoded = frame(FORMAT_ITEM, schema, item);
+        let page = self.data.last_mut().expect("No cache pages?");
+        page.extend_from_slice(&encoded).expect("Record too large.");
+        *self.counts.last_mut().expect("No counts?") += 1;
+    }
+
     pub fn read(&self) -> Vec<T> {
+        self.scan().0
+    }
+
+    /**
+      Decodes all records, returning them along with the number of records
+      that were corrupt or could not be decoded.
+    */
+    pub fn scan(&self) -> (Vec<T>, usize) {
         let mut results: Vec<T> = vec![];
+        let mut corrupt = self.dropped_corrupt;
 
         for page in &self.data {
-            let mut reader = BufReader::new(page.as_slice());
-            let mut error: Option<_> = None;
-
-            while error.is_none() {
-                match serde_bare::from_reader(&mut reader) {
-                    Ok(item) => {
-                        results.push(item);
-                    }
-                    Err(err) => {
-                        error = Some(err);
-                        // TODO: Distinguish real errors and the "error" of
-                        // reaching the end of the buffer.
-                    }
+            for record in records(page) {
+                match record.and_then(decode) {
+                    Some(items) => results.extend(items),
+                    None => corrupt += 1,
                 }
             }
         }
 
-        results
+        (results, corrupt)
     }
 
     pub fn stats(&self) -> BufferSummary {
         let items = self.counts.iter().sum();
         let bytes_used = self.data.iter().map(|v| v.len()).sum();
         let item_average_bytes = (bytes_used as f64 / items as f64) as f32;
+        let capacity = self.data.iter().map(|v| v.capacity()).sum();
+        let page_bytes = self.data.iter().map(|v| v.len()).collect();
+        let page_items = self.counts.to_vec();
+        let raw_bytes: usize = self.raw_bytes.iter().sum();
+        let compression_ratio = match bytes_used {
+            0 => 1.0,
+            _ => raw_bytes as f64 / bytes_used as f64,
+        };
 
         BufferSummary {
             items,
             bytes_used,
             item_average_bytes,
+            capacity,
+            page_bytes,
+            page_items,
+            rotations: self.rotations,
+            evicted: self.evicted,
+            reset_at: self.reset_at,
+            corrupt_records: 0,
+            compression_ratio,
         }
     }
 }
 
-impl<const N: usize, const M: usize, T: Serialize + DeserializeOwned> Default
-    for ShmemBackedSerdeRingBuffer<N, M, T>
-{
+impl<const N: usize, const M: usize, T: Versioned> Default for ShmemBackedSerdeRingBuffer<N, M, T> {
     fn default() -> Self {
         let _phantom = PhantomData;
         let mut data = heapless::Vec::default();
         let mut counts = heapless::Vec::default();
+        let mut raw_bytes = heapless::Vec::default();
         for _ in 0..data.capacity() {
             let msg = "Pushing too many elements here is impossible.";
             data.push(heapless::Vec::default()).expect(msg);
             counts.push(0).expect(msg);
+            raw_bytes.push(0).expect(msg);
         }
         ShmemBackedSerdeRingBuffer {
             data,
             counts,
+            raw_bytes,
+            rotations: 0,
+            evicted: 0,
+            dropped_corrupt: 0,
+            reset_at: None,
             _phantom,
         }
     }
@@ -150,4 +309,165 @@ pub struct BufferSummary {
     pub items: usize,
     pub bytes_used: usize,
     pub item_average_bytes: f32,
+    pub capacity: usize,
+    /// Bytes used in each page, from the oldest to the newest.
+    pub page_bytes: Vec<usize>,
+    pub page_items: Vec<usize>,
+    /// How many times the pages have been rotated, to start a new one.
+    pub rotations: u64,
+    /// Items cleared from the oldest page to make room for new ones.
+    pub evicted: u64,
+    /// When the cache was last cleared with `reset`.
+    pub reset_at: Option<OffsetDateTime>,
+    /// Records that failed their checksum or could not be decoded. Only
+    /// counted by a full scan, since that requires decoding every record.
+    pub corrupt_records: usize,
+    /// How many times larger the records would be, if they had not been
+    /// packed into blocks.
+    pub compression_ratio: f64,
+}
+
+fn frame(format: u8, schema: u16, payload: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(HEADER_SIZE + payload.len());
+    framed.push(format);
+    framed.extend_from_slice(&schema.to_le_bytes());
+    framed.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    framed.extend_from_slice(&checksum(schema, payload).to_le_bytes());
+    framed.extend_from_slice(payload);
+    framed
+}
+
+fn checksum(schema: u16, payload: &[u8]) -> u32 {
+    crc32(&[&schema.to_le_bytes(), payload])
+}
+
+fn decode<T: Versioned>((format, schema, payload): (u8, u16, &[u8])) -> Option<Vec<T>> {
+    match format {
+        FORMAT_ITEM => T::decode(schema, payload).ok().map(|item| vec![item]),
+        FORMAT_BLOCK => T::decode_block(schema, payload).ok(),
+        _ => None,
+    }
+}
+
+/**
+  Iterates over the records in a page, as their format, schema version and
+  payload. A record with a bad checksum is reported as corrupt -- `None` --
+  and skipped. A header that is cut off, or that claims more data than there
+  is, means the length can not be trusted; it is reported as corrupt, and the
+  rest of the page is skipped.
+*/
+fn records(page: &[u8]) -> impl Iterator<Item = Option<(u8, u16, &[u8])>> {
+    let mut pos = 0;
+
+    std::iter::from_fn(move || {
+        if pos >= page.len() {
+            return None;
+        }
+
+        let rest = &page[pos..];
+        if rest.len() < HEADER_SIZE {
+            pos = page.len();
+            return Some(None);
+        }
+
+        let format = rest[0];
+        let schema = u16::from_le_bytes([rest[1], rest[2]]);
+        let len = u32::from_le_bytes([rest[3], rest[4], rest[5], rest[6]]);
+        let sum = u32::from_le_bytes([rest[7], rest[8], rest[9], rest[10]]);
+        let end = HEADER_SIZE + len as usize;
+        if format == 0 || rest.len() < end {
+            pos = page.len();
+            return Some(None);
+        }
+
+        pos += end;
+        let payload = &rest[HEADER_SIZE..end];
+        if checksum(schema, payload) != sum {
+            return Some(None);
+        }
+
+        Some(Some((format, schema, payload)))
+    })
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/**
+  CRC-32 (IEEE), as used by zlib and Ethernet, of several slices in a row.
+*/
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for &b in parts.iter().flat_map(|part| part.iter()) {
+        crc = CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::{ShmemBackedSerdeRingBuffer, Versioned, FORMAT_BLOCK, HEADER_SIZE};
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Item(u32);
+
+    impl Versioned for Item {
+        const SCHEMA: u16 = 1;
+
+        fn encode_block(items: &[Self]) -> Option<Vec<u8>> {
+            serde_bare::to_vec(items).ok()
+        }
+
+        fn decode_block(_schema: u16, payload: &[u8]) -> anyhow::Result<Vec<Self>> {
+            Ok(serde_bare::from_slice(payload)?)
+        }
+    }
+
+    #[test]
+    fn counts_corrupt_records_dropped_when_compacting() {
+        let mut buffer = ShmemBackedSerdeRingBuffer::<256, 2, Item>::default();
+        for i in 0..3 {
+            buffer.write(&Item(i)).unwrap();
+        }
+        // Damage the payload of the second record.
+        let record = buffer.data[1].len() / 3;
+        buffer.data[1][record + HEADER_SIZE] ^= 0xFF;
+        assert_eq!(buffer.scan().1, 1);
+
+        // Fill the page, so that it is packed into a block.
+        for i in 3..20 {
+            buffer.write(&Item(i)).unwrap();
+        }
+        assert_eq!(buffer.data[1][0], FORMAT_BLOCK);
+        assert!(buffer.data[0].is_empty());
+
+        let (items, corrupt) = buffer.scan();
+        assert_eq!(corrupt, 1);
+        let expected: Vec<_> = (0..20).filter(|&i| i != 1).map(Item).collect();
+        assert_eq!(items, expected);
+
+        buffer.reset();
+        assert_eq!(buffer.scan(), (vec![], 0));
+    }
 }
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::fs;
+use std::time::{Duration, Instant};
+
+/**
+ Readings of the kernel's counters, like context switches since boot, from
+ the last two refreshes -- from which to compute rates of change.
+*/
+#[derive(Default)]
+pub struct Counters {
+    previous: Option<(Instant, HashMap<&'static str, u64>)>,
+    current: Option<(Instant, HashMap<&'static str, u64>)>,
+}
+
+// Rates measured over less time than this are mostly noise. The first
+// collection in a query backend, right after initialization, has no rates.
+const MINIMUM_WINDOW: Duration = Duration::from_millis(100);
+
+impl Counters {
+    pub fn update(&mut self, counters: HashMap<&'static str, u64>) {
+        self.previous = self.current.take();
+        self.current = Some((Instant::now(), counters));
+    }
+
+    /**
+     The per second rate of change of a counter. `None` when there are not yet two
+     readings far enough apart, or when the counter went backwards (it was
+     reset, or wrapped).
+    */
+    pub fn rate(&self, name: &str) -> Option<f64> {
+        let (then, before) = self.previous.as_ref()?;
+        let (now, after) = self.current.as_ref()?;
+        let elapsed = now.duration_since(*then);
+        if elapsed < MINIMUM_WINDOW {
+            return None;
+        }
+        let delta = after.get(name)?.checked_sub(*before.get(name)?)?;
+        Some(delta as f64 / elapsed.as_secs_f64())
+    }
+
+    /**
+     The latest reading, for values that are not cumulative.
+    */
+    pub fn value(&self, name: &str) -> Option<u64> {
+        self.current.as_ref()?.1.get(name).copied()
+    }
+}
+
+/**
+ Reads a file of lines that start with a name and a number -- like
+ `/proc/stat` or `/proc/vmstat` -- keeping the named lines. Further numbers
+ on a line are ignored.
+*/
+pub fn read_named_values(
+    path: &str,
+    names: &[&'static str],
+) -> anyhow::Result<HashMap<&'static str, u64>> {
+    let text = fs::read_to_string(path)?;
+    let mut values = HashMap::new();
+
+    for line in text.lines() {
+        let mut fields = line.split_whitespace();
+        let name = match fields.next().and_then(|n| names.iter().find(|&&k| k == n)) {
+            Some(name) => *name,
+            None => continue,
+        };
+        if let Some(Ok(value)) = fields.next().map(str::parse) {
+            values.insert(name, value);
+        }
+    }
+
+    Ok(values)
+}
@@ -0,0 +1,103 @@
+use std::time::{Duration, Instant};
+
+use sysinfo::{CpuExt, SystemExt};
+
+use super::{MetricInfo, MetricSource};
+use crate::collector::{sleep_interruptibly, Sample};
+use crate::cpu_baseline::{self, CpuTicks};
+use crate::settings;
+
+pub struct CpuSource {
+    client: sysinfo::System,
+    // CPU usage measured against the snapshot in shared memory, when there
+    // was one to measure against.
+    usage: Option<f64>,
+    refreshed_at: Option<Instant>,
+}
+
+impl CpuSource {
+    pub fn new() -> Self {
+        CpuSource {
+            client: sysinfo::System::new(),
+            usage: None,
+            refreshed_at: None,
+        }
+    }
+
+    // Whether to measure against the snapshot in shared memory, instead of
+    // against this source's own last refresh.
+    fn shared_baseline(&self) -> bool {
+        !cpu_baseline::is_publisher() && settings::shared_cpu_baseline()
+    }
+}
+
+const METRICS: &[MetricInfo] = &[MetricInfo {
+    name: "cpu_usage",
+    unit: "percent",
+    description: "Time spent on all CPUs doing work since the last refresh.",
+}];
+
+impl MetricSource for CpuSource {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn metrics(&self) -> &'static [MetricInfo] {
+        METRICS
+    }
+
+    fn warm_up(&self) -> Duration {
+        let min = sysinfo::System::MINIMUM_CPU_UPDATE_INTERVAL;
+        match cpu_baseline::age() {
+            Some(age) if age >= min && self.shared_baseline() => Duration::ZERO,
+            _ => min,
+        }
+    }
+
+    // Does not touch the snapshot in shared memory, which should be saved
+    // for the first real measurement.
+    fn initialize(&mut self) -> anyhow::Result<()> {
+        self.client.refresh_cpu();
+        self.refreshed_at = Some(Instant::now());
+        Ok(())
+    }
+
+    fn refresh(&mut self) -> anyhow::Result<()> {
+        let min = sysinfo::System::MINIMUM_CPU_UPDATE_INTERVAL;
+        self.usage = None;
+
+        // Without the counters -- on a system without `/proc/stat` -- there
+        // is always sysinfo's measurement to fall back on.
+        if let Ok(now) = CpuTicks::read() {
+            if cpu_baseline::is_publisher() {
+                cpu_baseline::publish(now);
+            } else if self.shared_baseline() {
+                if let Some(earlier) = cpu_baseline::take(now, min) {
+                    self.usage = now.usage_since(&earlier);
+                }
+            }
+        }
+
+        // `warm_up` skips the wait when the snapshot is old enough, but
+        // another backend may have taken it since. Then the wait is made up
+        // here, to measure against this source's own last refresh.
+        if self.usage.is_none() {
+            let passed = self.refreshed_at.map(|t| t.elapsed());
+            if let Some(remaining) = passed.and_then(|p| min.checked_sub(p)) {
+                sleep_interruptibly(remaining);
+            }
+        }
+        self.client.refresh_cpu();
+        self.refreshed_at = Some(Instant::now());
+
+        Ok(())
+    }
+
+    fn emit(&self, samples: &mut Vec<Sample>) {
+        let cpu_usage = match self.usage {
+            Some(usage) => usage,
+            None => self.client.global_cpu_info().cpu_usage() as f64,
+        };
+        samples.push(Sample::new("cpu_usage", &[], cpu_usage));
+    }
+}
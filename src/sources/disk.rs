@@ -0,0 +1,125 @@
+use std::collections::BTreeSet;
+
+use sysinfo::{DiskExt, SystemExt};
+
+use super::{usage_percent, Discovery, MetricInfo, MetricSource};
+use crate::collector::Sample;
+use crate::filter::Filter;
+
+pub struct DiskSource {
+    client: sysinfo::System,
+    filter: Filter,
+}
+
+impl DiskSource {
+    pub fn new() -> Self {
+        DiskSource {
+            client: sysinfo::System::new(),
+            filter: Filter::default(),
+        }
+    }
+}
+
+const METRICS: &[MetricInfo] = &[
+    MetricInfo {
+        name: "disk_usage",
+        unit: "percent",
+        description: "Space on the volume that is not available.",
+    },
+    MetricInfo {
+        name: "disk_size",
+        unit: "bytes",
+        description: "Total space on the volume.",
+    },
+    MetricInfo {
+        name: "disk_available",
+        unit: "bytes",
+        description: "Space on the volume available to unprivileged users.",
+    },
+];
+
+impl DiskSource {
+    fn mount_points(&self) -> BTreeSet<String> {
+        self.client
+            .disks()
+            .iter()
+            .map(|disk| disk.mount_point().to_string_lossy().into_owned())
+            .collect()
+    }
+}
+
+impl MetricSource for DiskSource {
+    fn name(&self) -> &'static str {
+        "disk"
+    }
+
+    fn metrics(&self) -> &'static [MetricInfo] {
+        METRICS
+    }
+
+    fn initialize(&mut self) -> anyhow::Result<()> {
+        self.client.refresh_disks_list();
+        Ok(())
+    }
+
+    fn refresh(&mut self) -> anyhow::Result<()> {
+        // This seems to be implemented in a way that is relatively fault
+        // tolerant. For example, if a disk was removed and can not be
+        // refreshed, it simply won't be updated.
+        self.client.refresh_disks();
+        Ok(())
+    }
+
+    fn emit(&self, samples: &mut Vec<Sample>) {
+        for disk in self.client.disks() {
+            let mount_point = disk.mount_point().to_string_lossy();
+            let file_system = String::from_utf8_lossy(disk.file_system());
+            if !self.filter.volume(&mount_point, &file_system) {
+                continue;
+            }
+            let vol = VolumeInfo::from(disk);
+            let dims = [("fs", vol.name.as_str())];
+            samples.extend([
+                Sample::new("disk_usage", &dims, vol.usage),
+                Sample::new("disk_size", &dims, vol.size),
+                Sample::new("disk_available", &dims, vol.available),
+            ]);
+        }
+    }
+
+    fn discover(&mut self) -> Discovery {
+        let before = self.mount_points();
+        self.client.refresh_disks_list();
+        let after = self.mount_points();
+
+        Discovery::between(&before, &after)
+    }
+
+    fn configure(&mut self, filter: &Filter) {
+        self.filter = filter.clone();
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VolumeInfo {
+    pub name: String,
+    pub size: f64,
+    pub available: f64,
+    pub usage: f64,
+}
+
+impl From<&sysinfo::Disk> for VolumeInfo {
+    fn from(disk: &sysinfo::Disk) -> Self {
+        let name = disk.mount_point().to_string_lossy().into_owned();
+        let size = disk.total_space() as f64;
+        let available = disk.available_space() as f64;
+        let usage = usage_percent(size, available);
+
+        VolumeInfo {
+            name,
+            size,
+            available,
+            usage,
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use std::fs;
+
+use pgrx::pg_sys;
+
+use super::{MetricInfo, MetricSource};
+use crate::collector::Sample;
+
+/**
+ System-wide tables that Postgres can exhaust with enough connections -- file
+ handles and process IDs -- and the postmaster's own file descriptors.
+
+ Counting processes reads a file for every process on the system, so on hosts
+ with many processes this source is best collected less often, with
+ `pg_stat_sysinfo.interval.limits`.
+*/
+pub struct LimitsSource {
+    values: Vec<(&'static str, f64)>,
+}
+
+impl LimitsSource {
+    pub fn new() -> Self {
+        LimitsSource { values: vec![] }
+    }
+}
+
+const METRICS: &[MetricInfo] = &[
+    MetricInfo {
+        name: "fs_file_handles_allocated",
+        unit: "files",
+        description: "File handles allocated by the kernel, for all processes.",
+    },
+    MetricInfo {
+        name: "fs_file_handles_max",
+        unit: "files",
+        description: "The most file handles the kernel will allocate.",
+    },
+    MetricInfo {
+        name: "processes_total",
+        unit: "processes",
+        description: "Processes on the system.",
+    },
+    MetricInfo {
+        name: "threads_total",
+        unit: "threads",
+        description: "Threads on the system, counting each process's main \
+                      thread.",
+    },
+    MetricInfo {
+        name: "zombie_processes",
+        unit: "processes",
+        description: "Processes that have exited but not been waited for.",
+    },
+    MetricInfo {
+        name: "pid_max",
+        unit: "processes",
+        description: "The highest process ID, which limits the number of \
+                      processes and threads.",
+    },
+    MetricInfo {
+        name: "postmaster_open_files",
+        unit: "files",
+        description: "File descriptors open in the postmaster.",
+    },
+    MetricInfo {
+        name: "postmaster_max_open_files",
+        unit: "files",
+        description: "The postmaster's limit on open file descriptors \
+                      (RLIMIT_NOFILE), inherited by every backend.",
+    },
+];
+
+impl MetricSource for LimitsSource {
+    fn name(&self) -> &'static str {
+        "limits"
+    }
+
+    fn metrics(&self) -> &'static [MetricInfo] {
+        METRICS
+    }
+
+    fn refresh(&mut self) -> anyhow::Result<()> {
+        let mut values = vec![];
+
+        let (allocated, max) = read_file_nr()?;
+        values.push(("fs_file_handles_allocated", allocated as f64));
+        values.push(("fs_file_handles_max", max as f64));
+
+        let tasks = count_tasks()?;
+        values.push(("processes_total", tasks.processes as f64));
+        values.push(("threads_total", tasks.threads as f64));
+        values.push(("zombie_processes", tasks.zombies as f64));
+
+        let pid_max: u64 = fs::read_to_string("/proc/sys/kernel/pid_max")?
+            .trim()
+            .parse()?;
+        values.push(("pid_max", pid_max as f64));
+
+        // Backends and the cache worker are forked from the postmaster, and
+        // run as the same user, so they may look at its file descriptors.
+        let postmaster = unsafe { pg_sys::PostmasterPid };
+        if let Ok(fds) = fs::read_dir(format!("/proc/{postmaster}/fd")) {
+            values.push(("postmaster_open_files", fds.count() as f64));
+        }
+        if let Ok(Some(limit)) = read_open_files_limit(postmaster) {
+            values.push(("postmaster_max_open_files", limit as f64));
+        }
+
+        self.values = values;
+        Ok(())
+    }
+
+    fn emit(&self, samples: &mut Vec<Sample>) {
+        for (metric, value) in &self.values {
+            samples.push(Sample::new(metric, &[], *value));
+        }
+    }
+}
+
+/**
+ Allocated and maximum file handles, from `/proc/sys/fs/file-nr`. (The
+ middle number, of free handles, is always zero on modern kernels.)
+*/
+fn read_file_nr() -> anyhow::Result<(u64, u64)> {
+    let text = fs::read_to_string("/proc/sys/fs/file-nr")?;
+    let numbers = text
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<u64>, _>>()?;
+    match numbers[..] {
+        [allocated, _, max] => Ok((allocated, max)),
+        _ => Err(anyhow::anyhow!("Expected 3 numbers in file-nr.")),
+    }
+}
+
+#[derive(Default)]
+struct Tasks {
+    processes: u64,
+    threads: u64,
+    zombies: u64,
+}
+
+/**
+ Counts processes, threads and zombies from `/proc/<pid>/stat`. Processes
+ that exit while we are looking are skipped.
+*/
+fn count_tasks() -> anyhow::Result<Tasks> {
+    let mut tasks = Tasks::default();
+
+    for entry in fs::read_dir("/proc")? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let name = entry.file_name();
+        if !name.to_string_lossy().bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        let stat = match fs::read_to_string(entry.path().join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        // The command name, in parentheses, may contain spaces and
+        // parentheses of its own. The state is the first field after it, and
+        // the number of threads the eighteenth.
+        let fields: Vec<&str> = match stat.rsplit_once(')') {
+            Some((_, rest)) => rest.split_whitespace().collect(),
+            None => continue,
+        };
+        tasks.processes += 1;
+        if fields.first() == Some(&"Z") {
+            tasks.zombies += 1;
+        }
+        if let Some(Ok(threads)) = fields.get(17).map(|n| n.parse::<u64>()) {
+            tasks.threads += threads;
+        }
+    }
+
+    Ok(tasks)
+}
+
+/**
+ The soft limit on open files, from `/proc/<pid>/limits`. `None` when it is
+ unlimited.
+*/
+fn read_open_files_limit(pid: i32) -> anyhow::Result<Option<u64>> {
+    let text = fs::read_to_string(format!("/proc/{pid}/limits"))?;
+    let line = text
+        .lines()
+        .find(|line| line.starts_with("Max open files"))
+        .ok_or_else(|| anyhow::anyhow!("No open files limit for {}.", pid))?;
+    let soft = line["Max open files".len()..]
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No open files limit for {}.", pid))?;
+    Ok(soft.parse().ok())
+}
@@ -0,0 +1,60 @@
+use sysinfo::SystemExt;
+
+use super::{MetricInfo, MetricSource};
+use crate::collector::Sample;
+
+pub struct LoadSource {
+    client: sysinfo::System,
+    load: Load,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Load {
+    pub min1: f64,
+    pub min5: f64,
+    pub min15: f64,
+}
+
+impl LoadSource {
+    pub fn new() -> Self {
+        LoadSource {
+            client: sysinfo::System::new(),
+            load: Load::default(),
+        }
+    }
+}
+
+const METRICS: &[MetricInfo] = &[MetricInfo {
+    name: "load_average",
+    unit: "tasks",
+    description: "Average number of runnable and waiting tasks.",
+}];
+
+impl MetricSource for LoadSource {
+    fn name(&self) -> &'static str {
+        "load"
+    }
+
+    fn metrics(&self) -> &'static [MetricInfo] {
+        METRICS
+    }
+
+    fn refresh(&mut self) -> anyhow::Result<()> {
+        let load_average = self.client.load_average();
+        self.load = Load {
+            min1: load_average.one,
+            min5: load_average.five,
+            min15: load_average.fifteen,
+        };
+        Ok(())
+    }
+
+    fn emit(&self, samples: &mut Vec<Sample>) {
+        let load = &self.load;
+        samples.extend([
+            Sample::new("load_average", &[("duration", "1m")], load.min1),
+            Sample::new("load_average", &[("duration", "5m")], load.min5),
+            Sample::new("load_average", &[("duration", "15m")], load.min15),
+        ]);
+    }
+}
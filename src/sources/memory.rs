@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+use sysinfo::SystemExt;
+
+use super::counters::{read_named_values, Counters};
+use super::{usage_percent, MetricInfo, MetricSource};
+use crate::collector::Sample;
+
+/**
+ Memory and swap, and paging activity. Note that all sizes are stored as
+ `f64`, which allows for exact representation of up to 8192 terabytes.
+*/
+pub struct MemorySource {
+    client: sysinfo::System,
+    vmstat: Counters,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        MemorySource {
+            client: sysinfo::System::new(),
+            vmstat: Counters::default(),
+        }
+    }
+}
+
+/**
+ Counters from `/proc/vmstat`, each the sum of the first group of fields
+ that the kernel has: newer kernels total some counters that older kernels
+ only report per memory zone, and vice versa.
+*/
+const VMSTAT: &[(&str, &[&[&str]])] = &[
+    ("pswpin", &[&["pswpin"]]),
+    ("pswpout", &[&["pswpout"]]),
+    ("pgmajfault", &[&["pgmajfault"]]),
+    ("pgfault", &[&["pgfault"]]),
+    (
+        "pgscan_direct",
+        &[
+            &["pgscan_direct"],
+            &[
+                "pgscan_direct_dma",
+                "pgscan_direct_dma32",
+                "pgscan_direct_normal",
+                "pgscan_direct_movable",
+            ],
+        ],
+    ),
+    (
+        "pgsteal",
+        &[
+            &["pgsteal_kswapd", "pgsteal_direct", "pgsteal_khugepaged"],
+            &[
+                "pgsteal_kswapd_dma",
+                "pgsteal_kswapd_dma32",
+                "pgsteal_kswapd_normal",
+                "pgsteal_kswapd_movable",
+                "pgsteal_direct_dma",
+                "pgsteal_direct_dma32",
+                "pgsteal_direct_normal",
+                "pgsteal_direct_movable",
+            ],
+        ],
+    ),
+    (
+        "allocstall",
+        &[
+            &["allocstall"],
+            &[
+                "allocstall_dma",
+                "allocstall_dma32",
+                "allocstall_normal",
+                "allocstall_movable",
+                "allocstall_device",
+            ],
+        ],
+    ),
+    ("oom_kill", &[&["oom_kill"]]),
+];
+
+fn read_vmstat() -> anyhow::Result<HashMap<&'static str, u64>> {
+    let names: Vec<&'static str> = VMSTAT
+        .iter()
+        .flat_map(|(_, groups)| groups.iter().flat_map(|group| group.iter()))
+        .copied()
+        .collect();
+    let fields = read_named_values("/proc/vmstat", &names)?;
+
+    let counters = VMSTAT
+        .iter()
+        .filter_map(|(counter, groups)| {
+            let group = groups
+                .iter()
+                .find(|group| group.iter().any(|f| fields.contains_key(f)))?;
+            let sum = group.iter().filter_map(|f| fields.get(f)).sum::<u64>();
+            Some((*counter, sum))
+        })
+        .collect();
+
+    Ok(counters)
+}
+
+const METRICS: &[MetricInfo] = &[
+    MetricInfo {
+        name: "memory_usage",
+        unit: "percent",
+        description: "Memory that is not available.",
+    },
+    MetricInfo {
+        name: "memory_size",
+        unit: "bytes",
+        description: "Total memory.",
+    },
+    MetricInfo {
+        name: "memory_available",
+        unit: "bytes",
+        description: "Memory that can be allocated, including by flushing \
+                      cache or buffers.",
+    },
+    MetricInfo {
+        name: "swap_usage",
+        unit: "percent",
+        description: "Swap that is in use.",
+    },
+    MetricInfo {
+        name: "swap_size",
+        unit: "bytes",
+        description: "Total swap.",
+    },
+    MetricInfo {
+        name: "swap_available",
+        unit: "bytes",
+        description: "Swap that is free.",
+    },
+    MetricInfo {
+        name: "pswpin_per_sec",
+        unit: "pages per second",
+        description: "Pages swapped in.",
+    },
+    MetricInfo {
+        name: "pswpout_per_sec",
+        unit: "pages per second",
+        description: "Pages swapped out.",
+    },
+    MetricInfo {
+        name: "pgmajfault_per_sec",
+        unit: "per second",
+        description: "Page faults that required reading from disk.",
+    },
+    MetricInfo {
+        name: "pgfault_per_sec",
+        unit: "per second",
+        description: "Page faults, major and minor.",
+    },
+    MetricInfo {
+        name: "pgscan_direct_per_sec",
+        unit: "pages per second",
+        description: "Pages scanned for reclaim by processes waiting to \
+                      allocate memory.",
+    },
+    MetricInfo {
+        name: "pgsteal_per_sec",
+        unit: "pages per second",
+        description: "Pages reclaimed.",
+    },
+    MetricInfo {
+        name: "allocstall_per_sec",
+        unit: "per second",
+        description: "Allocations that stalled, waiting for memory to be \
+                      reclaimed.",
+    },
+    MetricInfo {
+        name: "oom_kill_per_sec",
+        unit: "per second",
+        description: "Processes killed by the out-of-memory killer.",
+    },
+];
+
+impl MetricSource for MemorySource {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn metrics(&self) -> &'static [MetricInfo] {
+        METRICS
+    }
+
+    fn refresh(&mut self) -> anyhow::Result<()> {
+        self.client.refresh_memory();
+        // Paging activity is only available on Linux; memory and swap are
+        // reported regardless.
+        if let Ok(counters) = read_vmstat() {
+            self.vmstat.update(counters);
+        }
+        Ok(())
+    }
+
+    fn emit(&self, samples: &mut Vec<Sample>) {
+        let memory = Memory::from_total_and_available(
+            self.client.total_memory(),
+            // Why this stat: free means memory that is not used for anything,
+            // whereas available means memory that can be allocated, including
+            // by flushing cache or buffers. This seems to be more indicative
+            // of the real system state.
+            self.client.available_memory(),
+        );
+        let swap = Memory::from_total_and_available(
+            self.client.total_swap(),
+            // NB: No `available_swap` statistic.
+            self.client.free_swap(),
+        );
+
+        samples.extend([
+            Sample::new("memory_usage", &[], memory.usage),
+            Sample::new("memory_size", &[], memory.size),
+            Sample::new("memory_available", &[], memory.available),
+            Sample::new("swap_usage", &[], swap.usage),
+            Sample::new("swap_size", &[], swap.size),
+            Sample::new("swap_available", &[], swap.available),
+        ]);
+
+        for (counter, _) in VMSTAT {
+            if let Some(rate) = self.vmstat.rate(counter) {
+                let metric = format!("{counter}_per_sec");
+                samples.push(Sample::new(&metric, &[], rate));
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Memory {
+    pub size: f64,
+    pub available: f64,
+    pub usage: f64,
+}
+
+impl Memory {
+    fn from_total_and_available(size: u64, available: u64) -> Self {
+        let size = size as f64;
+        let available = available as f64;
+        let usage = usage_percent(size, available);
+
+        Memory {
+            size,
+            available,
+            usage,
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use crate::collector::Sample;
+use crate::filter::Filter;
+
+mod counters;
+mod cpu;
+mod disk;
+mod limits;
+mod load;
+mod memory;
+mod network;
+mod scheduler;
+mod temperature;
+
+/**
+ A source of metrics. Each source manages its own system caches, and is
+ refreshed and reported on independently of the others, so that a failure in
+ one source does not spoil a whole report.
+*/
+pub trait MetricSource: Send {
+    /**
+     A short name for the source, like `cpu` or `disk`.
+    */
+    fn name(&self) -> &'static str;
+
+    /**
+     The metrics this source emits.
+    */
+    fn metrics(&self) -> &'static [MetricInfo];
+
+    /**
+     How long must pass between refreshes for measurements to be meaningful.
+     Rates, like CPU usage, are computed from the difference between two
+     refreshes.
+    */
+    fn warm_up(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    /**
+     Anything that needs to be run before taking the first real
+     measurements.
+    */
+    fn initialize(&mut self) -> anyhow::Result<()> {
+        self.refresh()
+    }
+
+    fn refresh(&mut self) -> anyhow::Result<()>;
+
+    /**
+     Appends samples from the last refresh.
+    */
+    fn emit(&self, samples: &mut Vec<Sample>);
+
+    /**
+     Picks up devices that have come or gone since initialization. This
+     should be run once in an awhile, or due to device events or something of
+     that nature.
+    */
+    fn discover(&mut self) -> Discovery {
+        Discovery::default()
+    }
+
+    /**
+     Applies settings that narrow what the source reports on, beyond which
+     metrics are selected (for example, which volumes).
+    */
+    fn configure(&mut self, _filter: &Filter) {}
+}
+
+/**
+ Devices that came and went during discovery.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct Discovery {
+    pub appeared: Vec<String>,
+    pub disappeared: Vec<String>,
+}
+
+impl Discovery {
+    pub fn between(before: &BTreeSet<String>, after: &BTreeSet<String>) -> Self {
+        Discovery {
+            appeared: after.difference(before).cloned().collect(),
+            disappeared: before.difference(after).cloned().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.appeared.is_empty() && self.disappeared.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MetricInfo {
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub description: &'static str,
+}
+
+/**
+ All available sources, in the order they appear in reports.
+*/
+pub fn registry() -> Vec<Box<dyn MetricSource>> {
+    vec![
+        Box::new(load::LoadSource::new()),
+        Box::new(cpu::CpuSource::new()),
+        Box::new(scheduler::SchedulerSource::new()),
+        Box::new(memory::MemorySource::new()),
+        Box::new(network::NetworkSource::new()),
+        Box::new(limits::LimitsSource::new()),
+        Box::new(disk::DiskSource::new()),
+        Box::new(temperature::TemperatureSource::new()),
+    ]
+}
+
+pub fn names() -> Vec<&'static str> {
+    registry().iter().map(|source| source.name()).collect()
+}
+
+pub fn usage_percent(size: f64, available: f64) -> f64 {
+    100.0
+        * if size > 0.0 {
+            1.0 - available / size
+        } else {
+            0.0
+        }
+}
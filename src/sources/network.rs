@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::fs;
+
+use super::counters::Counters;
+use super::{MetricInfo, MetricSource};
+use crate::collector::Sample;
+
+/**
+ TCP activity from `/proc/net`: connections opened and established,
+ retransmits, overflowing listen queues, and sockets in use.
+*/
+pub struct NetworkSource {
+    counters: Counters,
+}
+
+impl NetworkSource {
+    pub fn new() -> Self {
+        NetworkSource {
+            counters: Counters::default(),
+        }
+    }
+}
+
+/**
+ Each metric, with its protocol, whether it is a rate (computed from a
+ counter) or a gauge, and the fields it is the sum of -- sockets are counted
+ separately for IPv4 and IPv6. Fields are named as in `/proc/net`, qualified
+ with their table (`Tcp.RetransSegs`, `TCP.inuse`). Counters are kept under
+ the first field.
+*/
+const NETSTAT: &[(&str, &str, bool, &[&str])] = &[
+    ("net_connections", "tcp", false, &["Tcp.CurrEstab"]),
+    (
+        "net_active_opens_per_sec",
+        "tcp",
+        true,
+        &["Tcp.ActiveOpens"],
+    ),
+    (
+        "net_passive_opens_per_sec",
+        "tcp",
+        true,
+        &["Tcp.PassiveOpens"],
+    ),
+    ("net_retransmits_per_sec", "tcp", true, &["Tcp.RetransSegs"]),
+    (
+        "net_listen_overflows_per_sec",
+        "tcp",
+        true,
+        &["TcpExt.ListenOverflows"],
+    ),
+    (
+        "net_listen_drops_per_sec",
+        "tcp",
+        true,
+        &["TcpExt.ListenDrops"],
+    ),
+    ("net_time_wait_sockets", "tcp", false, &["TCP.tw"]),
+    ("net_sockets", "tcp", false, &["TCP.inuse", "TCP6.inuse"]),
+];
+
+const METRICS: &[MetricInfo] = &[
+    MetricInfo {
+        name: "net_connections",
+        unit: "connections",
+        description: "TCP connections established, or closing on this end.",
+    },
+    MetricInfo {
+        name: "net_active_opens_per_sec",
+        unit: "per second",
+        description: "TCP connections opened to other hosts.",
+    },
+    MetricInfo {
+        name: "net_passive_opens_per_sec",
+        unit: "per second",
+        description: "TCP connections accepted from other hosts.",
+    },
+    MetricInfo {
+        name: "net_retransmits_per_sec",
+        unit: "segments per second",
+        description: "TCP segments sent again, because they were lost or not \
+                      acknowledged in time.",
+    },
+    MetricInfo {
+        name: "net_listen_overflows_per_sec",
+        unit: "per second",
+        description: "Connections refused because a listen queue was full.",
+    },
+    MetricInfo {
+        name: "net_listen_drops_per_sec",
+        unit: "per second",
+        description: "Connections to listening sockets dropped, for any \
+                      reason, including overflows.",
+    },
+    MetricInfo {
+        name: "net_time_wait_sockets",
+        unit: "sockets",
+        description: "TCP sockets in TIME_WAIT after closing.",
+    },
+    MetricInfo {
+        name: "net_sockets",
+        unit: "sockets",
+        description: "Sockets in use.",
+    },
+];
+
+impl MetricSource for NetworkSource {
+    fn name(&self) -> &'static str {
+        "network"
+    }
+
+    fn metrics(&self) -> &'static [MetricInfo] {
+        METRICS
+    }
+
+    fn refresh(&mut self) -> anyhow::Result<()> {
+        let mut fields = HashMap::new();
+        read_tables("/proc/net/snmp", &mut fields)?;
+        read_tables("/proc/net/netstat", &mut fields)?;
+        read_sockstat("/proc/net/sockstat", &mut fields)?;
+        // Missing when IPv6 is disabled.
+        let _ = read_sockstat("/proc/net/sockstat6", &mut fields);
+
+        let counters = NETSTAT
+            .iter()
+            .filter_map(|(_, _, _, names)| {
+                let values: Vec<u64> = names
+                    .iter()
+                    .filter_map(|name| fields.get(*name).copied())
+                    .collect();
+                (!values.is_empty()).then(|| (names[0], values.iter().sum()))
+            })
+            .collect();
+        self.counters.update(counters);
+
+        Ok(())
+    }
+
+    fn emit(&self, samples: &mut Vec<Sample>) {
+        for (metric, proto, rate, names) in NETSTAT {
+            let value = if *rate {
+                self.counters.rate(names[0])
+            } else {
+                self.counters.value(names[0]).map(|n| n as f64)
+            };
+            if let Some(value) = value {
+                samples.push(Sample::new(metric, &[("proto", *proto)], value));
+            }
+        }
+    }
+}
+
+/**
+ Reads a file of tables, like `/proc/net/snmp`, where each table is a line
+ of field names followed by a line of values, both starting with the name of
+ the table. Values that are not counters (like `Tcp.MaxConn`, which can be
+ -1) are skipped.
+*/
+fn read_tables(path: &str, fields: &mut HashMap<String, u64>) -> anyhow::Result<()> {
+    let text = fs::read_to_string(path)?;
+    let mut lines = text.lines();
+
+    while let (Some(names), Some(values)) = (lines.next(), lines.next()) {
+        let mut names = names.split_whitespace();
+        let mut values = values.split_whitespace();
+        let table = match (names.next(), values.next()) {
+            (Some(a), Some(b)) if a == b => a.trim_end_matches(':'),
+            _ => return Err(anyhow::anyhow!("Mismatched tables in {}.", path)),
+        };
+        for (name, value) in names.zip(values) {
+            if let Ok(value) = value.parse() {
+                fields.insert(format!("{table}.{name}"), value);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ Reads a file of lines like `TCP: inuse 4 orphan 0 tw 0 alloc 4 mem 0`, as
+ in `/proc/net/sockstat`.
+*/
+fn read_sockstat(path: &str, fields: &mut HashMap<String, u64>) -> anyhow::Result<()> {
+    let text = fs::read_to_string(path)?;
+
+    for line in text.lines() {
+        let mut words = line.split_whitespace();
+        let table = match words.next() {
+            Some(table) => table.trim_end_matches(':'),
+            None => continue,
+        };
+        while let (Some(name), Some(value)) = (words.next(), words.next()) {
+            if let Ok(value) = value.parse() {
+                fields.insert(format!("{table}.{name}"), value);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use super::counters::{read_named_values, Counters};
+use super::{MetricInfo, MetricSource};
+use crate::collector::Sample;
+
+/**
+ Scheduler activity from `/proc/stat`: how much work is queued up for the
+ CPUs, and how often they switch between tasks.
+*/
+pub struct SchedulerSource {
+    counters: Counters,
+}
+
+impl SchedulerSource {
+    pub fn new() -> Self {
+        SchedulerSource {
+            counters: Counters::default(),
+        }
+    }
+}
+
+const FIELDS: &[&str] = &[
+    "ctxt",
+    "intr",
+    "processes",
+    "procs_running",
+    "procs_blocked",
+];
+
+const METRICS: &[MetricInfo] = &[
+    MetricInfo {
+        name: "context_switches_per_sec",
+        unit: "per second",
+        description: "Context switches on all CPUs.",
+    },
+    MetricInfo {
+        name: "interrupts_per_sec",
+        unit: "per second",
+        description: "Interrupts serviced on all CPUs.",
+    },
+    MetricInfo {
+        name: "forks_per_sec",
+        unit: "per second",
+        description: "Processes and threads created.",
+    },
+    MetricInfo {
+        name: "procs_running",
+        unit: "tasks",
+        description: "Tasks running or ready to run on a CPU.",
+    },
+    MetricInfo {
+        name: "procs_blocked",
+        unit: "tasks",
+        description: "Tasks blocked waiting for I/O to complete.",
+    },
+];
+
+impl MetricSource for SchedulerSource {
+    fn name(&self) -> &'static str {
+        "scheduler"
+    }
+
+    fn metrics(&self) -> &'static [MetricInfo] {
+        METRICS
+    }
+
+    fn refresh(&mut self) -> anyhow::Result<()> {
+        self.counters
+            .update(read_named_values("/proc/stat", FIELDS)?);
+        Ok(())
+    }
+
+    fn emit(&self, samples: &mut Vec<Sample>) {
+        let rates = [
+            ("context_switches_per_sec", "ctxt"),
+            ("interrupts_per_sec", "intr"),
+            ("forks_per_sec", "processes"),
+        ];
+        for (metric, field) in rates {
+            if let Some(rate) = self.counters.rate(field) {
+                samples.push(Sample::new(metric, &[], rate));
+            }
+        }
+
+        let gauges = [
+            ("procs_running", "procs_running"),
+            ("procs_blocked", "procs_blocked"),
+        ];
+        for (metric, field) in gauges {
+            if let Some(value) = self.counters.value(field) {
+                samples.push(Sample::new(metric, &[], value as f64));
+            }
+        }
+    }
+}
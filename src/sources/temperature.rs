@@ -0,0 +1,278 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use super::{MetricInfo, MetricSource};
+use crate::collector::Sample;
+use crate::settings;
+
+/**
+ Hardware sensor temperatures, from the kernel's hwmon devices -- the same
+ sensors as sysinfo's components, which can only read them from `/sys`. Here
+ the root is a setting, so that fixture directories can stand in for it.
+*/
+pub struct TemperatureSource {
+    sensors: Vec<Sensor>,
+}
+
+impl TemperatureSource {
+    pub fn new() -> Self {
+        TemperatureSource { sensors: vec![] }
+    }
+}
+
+struct Sensor {
+    label: String,
+    temperature: f64,
+    max: Option<f64>,
+    critical: Option<f64>,
+}
+
+const METRICS: &[MetricInfo] = &[
+    MetricInfo {
+        name: "temperature_celsius",
+        unit: "degrees Celsius",
+        description: "Temperature at a hardware sensor.",
+    },
+    MetricInfo {
+        name: "temperature_max_celsius",
+        unit: "degrees Celsius",
+        description: "Temperature above which a sensor's hardware may be \
+                      throttled.",
+    },
+    MetricInfo {
+        name: "temperature_critical_celsius",
+        unit: "degrees Celsius",
+        description: "Temperature at which a sensor's hardware may shut down.",
+    },
+];
+
+impl MetricSource for TemperatureSource {
+    fn name(&self) -> &'static str {
+        "temperature"
+    }
+
+    fn metrics(&self) -> &'static [MetricInfo] {
+        METRICS
+    }
+
+    fn refresh(&mut self) -> anyhow::Result<()> {
+        self.sensors = read_sensors(Path::new(&settings::sysfs_root()))?;
+        Ok(())
+    }
+
+    fn emit(&self, samples: &mut Vec<Sample>) {
+        for sensor in &self.sensors {
+            let dims = [("sensor", sensor.label.as_str())];
+            samples.push(Sample::new(
+                "temperature_celsius",
+                &dims,
+                sensor.temperature,
+            ));
+            if let Some(max) = sensor.max {
+                samples.push(Sample::new("temperature_max_celsius", &dims, max));
+            }
+            if let Some(critical) = sensor.critical {
+                samples.push(Sample::new("temperature_critical_celsius", &dims, critical));
+            }
+        }
+    }
+}
+
+/**
+ Reads the `temp<N>_*` files of every device under `class/hwmon` in sysfs,
+ or under its `device` directory, where older drivers put them. Sensors are
+ labelled with the device's `name` and the sensor's own `temp<N>_label`,
+ like `coretemp Package id 0`, or `nvme temp1` when there is no label.
+*/
+fn read_sensors(sysfs: &Path) -> anyhow::Result<Vec<Sensor>> {
+    let hwmon = sysfs.join("class/hwmon");
+    // Virtual machines and containers often have no sensors at all.
+    if !hwmon.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut devices = fs::read_dir(&hwmon)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .collect::<Vec<_>>();
+    devices.sort();
+
+    let mut sensors = vec![];
+    let mut labels = HashSet::new();
+
+    for device in devices {
+        let dir = if has_temperatures(&device) {
+            device.clone()
+        } else {
+            device.join("device")
+        };
+        let name = read_string(&dir.join("name"))
+            .or_else(|| read_string(&device.join("name")))
+            .unwrap_or_default();
+
+        let mut inputs = match fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| {
+                    let file = entry.ok()?.file_name().into_string().ok()?;
+                    let n = file.strip_prefix("temp")?.strip_suffix("_input")?;
+                    n.parse::<u32>().ok()
+                })
+                .collect::<Vec<_>>(),
+            Err(_) => continue,
+        };
+        inputs.sort();
+
+        for n in inputs {
+            let temperature = match read_celsius(&dir.join(format!("temp{n}_input"))) {
+                Some(temperature) => temperature,
+                None => continue,
+            };
+            let sensor = read_string(&dir.join(format!("temp{n}_label")))
+                .unwrap_or_else(|| format!("temp{n}"));
+            let mut label = format!("{name} {sensor}").trim().to_string();
+            // Identical devices, like two NVMe drives, have identical labels.
+            if labels.contains(&label) {
+                let id = device.file_name().unwrap_or_default().to_string_lossy();
+                label = format!("{label} ({id})");
+            }
+            labels.insert(label.clone());
+
+            sensors.push(Sensor {
+                label,
+                temperature,
+                max: read_celsius(&dir.join(format!("temp{n}_max"))),
+                critical: read_celsius(&dir.join(format!("temp{n}_crit"))),
+            });
+        }
+    }
+
+    Ok(sensors)
+}
+
+fn has_temperatures(dir: &Path) -> bool {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(Result::ok).any(|entry| {
+            let file = entry.file_name();
+            let file = file.to_string_lossy();
+            file.starts_with("temp") && file.ends_with("_input")
+        }),
+        Err(_) => false,
+    }
+}
+
+fn read_string(path: &Path) -> Option<String> {
+    let text = fs::read_to_string(path).ok()?;
+    Some(text.trim().to_string()).filter(|s| !s.is_empty())
+}
+
+// Temperatures are in millidegrees Celsius.
+fn read_celsius(path: &Path) -> Option<f64> {
+    let millidegrees: i64 = read_string(path)?.parse().ok()?;
+    Some(millidegrees as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::read_sensors;
+
+    /**
+     A directory standing in for sysfs, removed when dropped.
+    */
+    struct Sysfs(PathBuf);
+
+    impl Sysfs {
+        fn new(name: &str) -> Self {
+            let pid = std::process::id();
+            let root = std::env::temp_dir().join(format!("pg_stat_sysinfo-{name}-{pid}"));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            Sysfs(root)
+        }
+
+        fn file(&self, path: &str, contents: &str) -> &Self {
+            let path = self.0.join("class/hwmon").join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+            self
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for Sysfs {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn readings(sysfs: &Sysfs) -> Vec<(String, f64, Option<f64>, Option<f64>)> {
+        read_sensors(sysfs.path())
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.label, s.temperature, s.max, s.critical))
+            .collect()
+    }
+
+    #[test]
+    fn labels_sensors_by_device_and_label() {
+        let sysfs = Sysfs::new("labels");
+        sysfs
+            .file("hwmon0/name", "coretemp\n")
+            .file("hwmon0/temp1_input", "45000\n")
+            .file("hwmon0/temp1_label", "Package id 0\n")
+            .file("hwmon0/temp1_max", "80000\n")
+            .file("hwmon0/temp1_crit", "100000\n")
+            .file("hwmon0/temp2_input", "43500\n");
+
+        assert_eq!(
+            readings(&sysfs),
+            vec![
+                (
+                    "coretemp Package id 0".into(),
+                    45.0,
+                    Some(80.0),
+                    Some(100.0)
+                ),
+                ("coretemp temp2".into(), 43.5, None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_sensors_under_device() {
+        let sysfs = Sysfs::new("device");
+        sysfs
+            .file("hwmon0/device/name", "acpitz\n")
+            .file("hwmon0/device/temp1_input", "27800\n")
+            .file("hwmon0/device/temp1_crit", "119000\n");
+
+        assert_eq!(
+            readings(&sysfs),
+            vec![("acpitz temp1".into(), 27.8, None, Some(119.0))]
+        );
+    }
+
+    #[test]
+    fn distinguishes_identical_devices() {
+        let sysfs = Sysfs::new("duplicates");
+        sysfs
+            .file("hwmon1/name", "nvme\n")
+            .file("hwmon1/temp1_input", "38850\n")
+            .file("hwmon2/name", "nvme\n")
+            .file("hwmon2/temp1_input", "39000\n");
+
+        let labels: Vec<String> = readings(&sysfs).into_iter().map(|r| r.0).collect();
+        assert_eq!(labels, vec!["nvme temp1", "nvme temp1 (hwmon2)"]);
+    }
+
+    #[test]
+    fn has_no_sensors_without_hwmon() {
+        let sysfs = Sysfs::new("missing");
+        assert!(readings(&sysfs).is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use crate::collector::Report;
+
+/**
+ Summary statistics over the cached samples of one metric, for one set of
+ dimensions.
+*/
+#[derive(Clone, Debug)]
+pub struct Summary {
+    pub metric: String,
+    pub dimensions: Value,
+    pub samples: usize,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    /// Sample standard deviation, like Postgres's `stddev()`; `None` for a
+    /// single sample.
+    pub stddev: Option<f64>,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub last: f64,
+    pub last_at: OffsetDateTime,
+}
+
+/**
+ Summarizes the reports taken at or after `since`.
+*/
+pub fn summarize(reports: &[Report], since: OffsetDateTime) -> Vec<Summary> {
+    type Series = (String, Value, Vec<f64>, OffsetDateTime, f64);
+    let mut series: BTreeMap<(String, String), Series> = BTreeMap::new();
+
+    for report in reports.iter().filter(|r| r.at >= since) {
+        for (metric, dimensions, at, value) in report.rows() {
+            if value.is_nan() {
+                continue;
+            }
+            let key = (metric.clone(), dimensions.to_string());
+            let entry = series
+                .entry(key)
+                .or_insert_with(|| (metric, dimensions, vec![], at, value));
+            entry.2.push(value);
+            if at >= entry.3 {
+                entry.3 = at;
+                entry.4 = value;
+            }
+        }
+    }
+
+    series
+        .into_values()
+        .map(|(metric, dimensions, mut values, last_at, last)| {
+            values.sort_by(|a, b| a.total_cmp(b));
+            let n = values.len() as f64;
+            let avg = values.iter().sum::<f64>() / n;
+            let stddev = (values.len() > 1).then(|| {
+                let ss: f64 = values.iter().map(|v| (v - avg).powi(2)).sum();
+                (ss / (n - 1.0)).sqrt()
+            });
+
+            Summary {
+                metric,
+                dimensions,
+                samples: values.len(),
+                min: values[0],
+                max: values[values.len() - 1],
+                avg,
+                stddev,
+                p50: percentile(&values, 0.50),
+                p95: percentile(&values, 0.95),
+                p99: percentile(&values, 0.99),
+                last,
+                last_at,
+            }
+        })
+        .collect()
+}
+
+/**
+ Interpolated percentile of sorted values, like `percentile_cont`.
+*/
+fn percentile(sorted: &[f64], fraction: f64) -> f64 {
+    let rank = fraction * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    let weight = rank - lower as f64;
+
+    sorted[lower] + (sorted[upper] - sorted[lower]) * weight
+}
+
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+
+    use super::{percentile, summarize};
+    use crate::collector::{Report, Sample};
+
+    fn at(seconds: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(1_674_000_000 + seconds).unwrap()
+    }
+
+    fn reports(points: &[(i64, f64)]) -> Vec<Report> {
+        points
+            .iter()
+            .map(|&(seconds, value)| Report {
+                at: at(seconds),
+                samples: vec![Sample::new("cpu_usage", &[], value)],
+            })
+            .collect()
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn percentiles_interpolate_like_percentile_cont() {
+        let values = [1.0, 2.0, 3.0, 4.0];
+        assert_close(percentile(&values, 0.0), 1.0);
+        assert_close(percentile(&values, 0.5), 2.5);
+        assert_close(percentile(&values, 0.95), 3.85);
+        assert_close(percentile(&values, 0.99), 3.97);
+        assert_close(percentile(&values, 1.0), 4.0);
+        assert_close(percentile(&[7.0], 0.95), 7.0);
+    }
+
+    #[test]
+    fn summarizes_values() {
+        let summaries = summarize(
+            &reports(&[(0, 4.0), (10, 1.0), (20, 3.0), (30, 2.0)]),
+            at(0),
+        );
+        assert_eq!(summaries.len(), 1);
+        let s = &summaries[0];
+        assert_eq!(s.metric, "cpu_usage");
+        assert_eq!(s.samples, 4);
+        assert_eq!((s.min, s.max), (1.0, 4.0));
+        assert_close(s.avg, 2.5);
+        // Sample standard deviation, with n - 1 degrees of freedom.
+        assert_close(s.stddev.unwrap(), (5.0f64 / 3.0).sqrt());
+        assert_close(s.p50, 2.5);
+        assert_eq!((s.last, s.last_at), (2.0, at(30)));
+    }
+
+    #[test]
+    fn single_sample_has_no_stddev() {
+        let summaries = summarize(&reports(&[(0, 42.0)]), at(0));
+        let s = &summaries[0];
+        assert_eq!(s.samples, 1);
+        assert_eq!(s.stddev, None);
+        assert_eq!((s.min, s.max, s.avg, s.p99), (42.0, 42.0, 42.0, 42.0));
+    }
+
+    #[test]
+    fn excludes_samples_before_the_window() {
+        let reports = reports(&[(0, 100.0), (10, 1.0), (20, 3.0)]);
+        let s = &summarize(&reports, at(10))[0];
+        assert_eq!(s.samples, 2);
+        assert_eq!(s.max, 3.0);
+        assert_close(s.avg, 2.0);
+
+        assert!(summarize(&reports, at(21)).is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+use std::panic::catch_unwind;
+use std::time::Duration;
+
+use pgrx::*;
+use time::OffsetDateTime;
+
+const ERROR_MESSAGE_SIZE: usize = 256;
+
+static STATUS: PgLwLock<WorkerStatus> = PgLwLock::new();
+
+unsafe impl PGRXSharedMemory for WorkerStatus {}
+
+pub fn init() {
+    pg_shmem_init!(STATUS);
+}
+
+/**
+ The status of the cache worker, kept in shared memory and updated by the
+ worker on every iteration of its loop. `None` when the library was not
+ loaded with `shared_preload_libraries`.
+*/
+pub fn read() -> Option<WorkerStatus> {
+    // See `cache_worker::reports()` for why we catch the panic.
+    catch_unwind(|| STATUS.share().clone()).ok()
+}
+
+/**
+ Updates the status. Only the cache worker should call this.
+*/
+pub fn update(f: impl FnOnce(&mut WorkerStatus)) {
+    f(&mut STATUS.exclusive())
+}
+
+/**
+ Records this process as the cache worker, unless another cache worker is
+ already running. Checking and claiming happen under the same lock, so that
+ of two workers starting at once, only one runs.
+*/
+pub fn claim(pid: i32) -> bool {
+    let mut status = STATUS.exclusive();
+    let other = status.pid != 0 && status.pid != pid;
+    if other && unsafe { !pg_sys::BackendPidGetProc(status.pid).is_null() } {
+        return false;
+    }
+    status.pid = pid;
+    true
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct WorkerStatus {
+    /// The process ID of the running worker, or zero.
+    pub pid: i32,
+    pub enabled: bool,
+    pub interval: Option<Duration>,
+    pub last_collection: Option<OffsetDateTime>,
+    pub last_duration: Option<Duration>,
+    pub next_run: Option<OffsetDateTime>,
+    pub collections: u64,
+    /// Collections that ran noticeably later than scheduled.
+    pub late_ticks: u64,
+    /// Collections that did not run at all, because the worker was busy or
+    /// blocked for more than an interval.
+    pub missed_ticks: u64,
+    pub write_errors: u64,
+    pub last_error: heapless::String<ERROR_MESSAGE_SIZE>,
+    pub last_error_at: Option<OffsetDateTime>,
+}
+
+impl WorkerStatus {
+    /**
+     Counts a collection that ran `late` after it was scheduled.
+    */
+    pub fn count_lateness(&mut self, late: Duration, interval: Duration) {
+        if interval.is_zero() {
+            return;
+        }
+        let missed = (late.as_nanos() / interval.as_nanos()) as u64;
+        if missed > 0 {
+            self.missed_ticks += missed;
+        } else if late > interval / 10 {
+            self.late_ticks += 1;
+        }
+    }
+
+    /**
+     Records an error message, truncated to fit in shared memory.
+    */
+    pub fn record_error(&mut self, message: &str) {
+        let mut end = message.len().min(ERROR_MESSAGE_SIZE);
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.last_error.clear();
+        // Can not fail, since the message has been truncated to fit.
+        let _ = self.last_error.push_str(&message[..end]);
+        self.last_error_at = Some(OffsetDateTime::now_utc());
+    }
+}